@@ -0,0 +1,14 @@
+fn main() {
+    // the result messages are written out in src/proto.rs, only the service is generated
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .extern_path(".face_detection.ImageSize", "crate::proto::ImageSize")
+        .extern_path(".face_detection.Detection", "crate::proto::Detection")
+        .extern_path(
+            ".face_detection.DetectionResult",
+            "crate::proto::DetectionResult",
+        )
+        .compile(&["proto/detection.proto"], &["proto"])
+        .unwrap();
+}
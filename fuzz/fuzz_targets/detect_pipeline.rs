@@ -0,0 +1,82 @@
+#![no_main]
+
+use face_detection_server::{
+    config::{ChannelOrder, InputLayout, NmsMode},
+    detection::decode_image,
+    ultra_predictor::{
+        decode_candidates, get_image_tensor, has_aspect_ratio, map_bboxes_to_bbox_with_pixels,
+        merge_detections, post_process, resize_for_model,
+    },
+};
+use image::{imageops::FilterType, io::Limits, ImageFormat};
+use libfuzzer_sys::fuzz_target;
+use ndarray::Array;
+
+/// Decode limits like `DECODE_MAX_*`, small enough that the fuzzer does not spend its time
+/// allocating huge images.
+const MAX_DIMENSION: u32 = 4096;
+const MAX_ALLOC: u64 = 64 * 1024 * 1024;
+/// `MIN_BOX_ASPECT_RATIO` and `MAX_BOX_ASPECT_RATIO` wide open, so every box reaches the merge.
+const ASPECT_RATIO: (f32, f32) = (0.0, f32::MAX);
+
+// Feeds the input as an upload through decoding and preprocessing, and reinterpreted as model
+// outputs through post-processing, the aspect ratio filter and the merge of tiles and
+// orientations, the paths that handle untrusted bytes. Any float is a valid model output here,
+// including NaN confidences and inverted boxes.
+fuzz_target!(|data: &[u8]| {
+    let (width, height) = match decode_and_preprocess(data) {
+        Some(size) => size,
+        None => (640, 480),
+    };
+
+    // the first byte picks the number of classes, the rest are the confidences of each box
+    // followed by its 4 coordinates
+    let Some((&classes, outputs)) = data.split_first() else {
+        return;
+    };
+    let classes = classes as usize % 4;
+    let values: Vec<f32> = outputs
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+    let boxes = values.len() / (classes + 4);
+    let (confidences, bboxes) = values[..boxes * (classes + 4)]
+        .chunks_exact(classes + 4)
+        .map(|candidate| candidate.split_at(classes))
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+    let confidences = Array::from_shape_vec((1, boxes, classes), confidences.concat())
+        .unwrap()
+        .into_dyn();
+    let bboxes = Array::from_shape_vec((1, boxes, 4), bboxes.concat())
+        .unwrap()
+        .into_dyn();
+
+    let Ok(candidates) = decode_candidates(confidences.view(), bboxes.view(), None) else {
+        return;
+    };
+    for nms_mode in [NmsMode::Hard, NmsMode::WeightedFusion] {
+        let bboxes_with_confidences = post_process(&candidates, 0.7, 0.0, false, nms_mode, 1000);
+        let mut detections = map_bboxes_to_bbox_with_pixels(width, height, bboxes_with_confidences);
+        detections.retain(|(bbox, _)| has_aspect_ratio(bbox, ASPECT_RATIO));
+        merge_detections(detections);
+    }
+});
+
+/// Decode the input like an upload and build the model input from it, returning the image size.
+fn decode_and_preprocess(data: &[u8]) -> Option<(u32, u32)> {
+    let format = match image::guess_format(data) {
+        Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg)) => format,
+        _ => return None,
+    };
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    let image = decode_image(data, format, limits).ok()?;
+    get_image_tensor(
+        &resize_for_model(&image, FilterType::Triangle, true),
+        InputLayout::Nchw,
+        ChannelOrder::Rgb,
+    );
+    Some((image.width(), image.height()))
+}
@@ -0,0 +1,125 @@
+use std::{fs, io, path::Path, sync::RwLock, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    detection::DetectionResult,
+    result_file::{read_result, COMPRESSED_EXTENSION},
+};
+
+/// Confidence histogram buckets, each 0.1 wide.
+static CONFIDENCE_BUCKETS: usize = 10;
+
+/// Aggregates over the stored result files, computed by `Analytics::refresh`.
+#[derive(Clone, Serialize)]
+pub struct AnalyticsSummary {
+    /// Results with detections, including those without faces.
+    pub results: u64,
+    /// Error results of failed jobs.
+    pub failed: u64,
+    pub total_faces: u64,
+    pub avg_faces_per_image: f64,
+    pub avg_confidence: f64,
+    /// Face counts per confidence bucket, `[0.0, 0.1)` first and `[0.9, 1.0]` last.
+    pub confidence_histogram: Vec<u64>,
+    /// The scan stopped at the result limit, the aggregates only cover part of the results.
+    pub truncated: bool,
+    /// Unix time of the scan.
+    pub computed_at: u64,
+}
+
+/// A result file is either a detection result or the error of a failed job.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredResult {
+    Detection(DetectionResult),
+    #[allow(dead_code)]
+    Error {
+        error: String,
+    },
+}
+
+/// Latest aggregates over the results directory. Scanning is too slow to do per request, so a
+/// background task refreshes them on an interval and requests read the last scan.
+pub struct Analytics {
+    summary: RwLock<Option<AnalyticsSummary>>,
+}
+
+impl Analytics {
+    pub fn new() -> Analytics {
+        Analytics {
+            summary: RwLock::new(None),
+        }
+    }
+
+    /// The last scan, `None` until the first one finished.
+    pub fn summary(&self) -> Option<AnalyticsSummary> {
+        self.summary.read().unwrap().clone()
+    }
+
+    /// Scan at most `max_results` result files in `results_dir` and replace the summary.
+    pub fn refresh(&self, results_dir: &Path, max_results: usize) -> io::Result<()> {
+        let summary = scan_results(results_dir, max_results)?;
+        *self.summary.write().unwrap() = Some(summary);
+        Ok(())
+    }
+}
+
+fn scan_results(results_dir: &Path, max_results: usize) -> io::Result<AnalyticsSummary> {
+    let mut summary = AnalyticsSummary {
+        results: 0,
+        failed: 0,
+        total_faces: 0,
+        avg_faces_per_image: 0.0,
+        avg_confidence: 0.0,
+        confidence_histogram: vec![0; CONFIDENCE_BUCKETS],
+        truncated: false,
+        computed_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    let mut confidence_sum = 0.0;
+    let mut scanned = 0;
+
+    for entry in fs::read_dir(results_dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        // hidden files are results still being written
+        let is_result = file_name.ends_with(".json") || file_name.ends_with(COMPRESSED_EXTENSION);
+        if file_name.starts_with('.') || !is_result {
+            continue;
+        }
+        if scanned >= max_results {
+            summary.truncated = true;
+            break;
+        }
+        scanned += 1;
+
+        let result = read_result(&path)
+            .ok()
+            .and_then(|result| serde_json::from_slice(&result).ok());
+        match result {
+            Some(StoredResult::Detection(result)) => {
+                summary.results += 1;
+                for (_, confidence) in result.detections {
+                    summary.total_faces += 1;
+                    confidence_sum += confidence as f64;
+                    let bucket = (confidence.clamp(0.0, 1.0) * CONFIDENCE_BUCKETS as f32) as usize;
+                    summary.confidence_histogram[bucket.min(CONFIDENCE_BUCKETS - 1)] += 1;
+                }
+            }
+            Some(StoredResult::Error { .. }) => summary.failed += 1,
+            // removed since listing the directory, or not a result
+            None => {}
+        }
+    }
+
+    if summary.results > 0 {
+        summary.avg_faces_per_image = summary.total_faces as f64 / summary.results as f64;
+    }
+    if summary.total_faces > 0 {
+        summary.avg_confidence = confidence_sum / summary.total_faces as f64;
+    }
+    Ok(summary)
+}
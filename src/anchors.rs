@@ -0,0 +1,87 @@
+/// Anchor (prior box) sizes in pixels per feature map, and the stride of each feature map, of the
+/// ultra-light face detector.
+static MIN_BOXES: [&[f32]; 4] = [
+    &[10.0, 16.0, 24.0],
+    &[32.0, 48.0],
+    &[64.0, 96.0],
+    &[128.0, 192.0, 256.0],
+];
+static STRIDES: [f32; 4] = [8.0, 16.0, 32.0, 64.0];
+pub static DEFAULT_CENTER_VARIANCE: f32 = 0.1;
+pub static DEFAULT_SIZE_VARIANCE: f32 = 0.2;
+
+/// Decoding of models that output box regressions relative to prior boxes instead of corners.
+pub struct Anchors {
+    /// `[x_center, y_center, width, height]` relative to the input size.
+    priors: Vec<[f32; 4]>,
+    center_variance: f32,
+    size_variance: f32,
+}
+
+impl Anchors {
+    /// Generate the SSD style prior boxes for a model input of `input_width` x `input_height`.
+    pub fn new(
+        input_width: usize,
+        input_height: usize,
+        center_variance: f32,
+        size_variance: f32,
+    ) -> Anchors {
+        let (width, height) = (input_width as f32, input_height as f32);
+        let mut priors = Vec::new();
+        for (min_boxes, stride) in MIN_BOXES.iter().zip(STRIDES) {
+            let feature_map_width = (width / stride).ceil() as usize;
+            let feature_map_height = (height / stride).ceil() as usize;
+            for y in 0..feature_map_height {
+                for x in 0..feature_map_width {
+                    let x_center = (x as f32 + 0.5) * stride / width;
+                    let y_center = (y as f32 + 0.5) * stride / height;
+                    for min_box in min_boxes.iter() {
+                        priors.push([
+                            x_center.clamp(0.0, 1.0),
+                            y_center.clamp(0.0, 1.0),
+                            (min_box / width).clamp(0.0, 1.0),
+                            (min_box / height).clamp(0.0, 1.0),
+                        ]);
+                    }
+                }
+            }
+        }
+
+        Anchors {
+            priors,
+            center_variance,
+            size_variance,
+        }
+    }
+
+    /// Number of candidate boxes the model has to output.
+    pub fn len(&self) -> usize {
+        self.priors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.priors.is_empty()
+    }
+
+    /// Decode box regressions, in the order of the model outputs, into `[x_top_left, y_top_left,
+    /// x_bottom_right, y_bottom_right]` relative to the input size.
+    pub fn decode(&self, regressions: &[[f32; 4]]) -> Vec<[f32; 4]> {
+        regressions
+            .iter()
+            .zip(self.priors.iter())
+            .map(|(regression, prior)| {
+                let [prior_x, prior_y, prior_width, prior_height] = *prior;
+                let x_center = regression[0] * self.center_variance * prior_width + prior_x;
+                let y_center = regression[1] * self.center_variance * prior_height + prior_y;
+                let width = (regression[2] * self.size_variance).exp() * prior_width;
+                let height = (regression[3] * self.size_variance).exp() * prior_height;
+                [
+                    x_center - width / 2.0,
+                    y_center - height / 2.0,
+                    x_center + width / 2.0,
+                    y_center + height / 2.0,
+                ]
+            })
+            .collect()
+    }
+}
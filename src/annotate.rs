@@ -0,0 +1,71 @@
+use image::{Rgb, RgbImage};
+use imageproc::{
+    drawing::{draw_hollow_rect_mut, draw_text_mut},
+    rect::Rect,
+};
+use rusttype::{Font, Scale};
+
+use crate::ultra_predictor::BboxPixels;
+
+static PLAIN_COLOR: Rgb<u8> = Rgb([0, 255, 0]);
+static BOX_THICKNESS: u32 = 2;
+static LABEL_SCALE: f32 = 16.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BoxColors {
+    /// Every box is drawn in the same color.
+    Plain,
+    /// The box color is picked from the palette by confidence.
+    Confidence,
+}
+
+pub struct AnnotationStyle<'a> {
+    pub colors: BoxColors,
+    /// `(minimum confidence, color)` pairs sorted by descending confidence.
+    pub palette: &'a [(f32, Rgb<u8>)],
+    /// Draw the confidence above each box when a font is given.
+    pub font: Option<&'a Font<'static>>,
+}
+
+/// Draw the detected boxes onto `image`.
+pub fn annotate(image: &mut RgbImage, detections: &[(BboxPixels, f32)], style: &AnnotationStyle) {
+    for (bbox, confidence) in detections {
+        let color = match style.colors {
+            BoxColors::Plain => PLAIN_COLOR,
+            BoxColors::Confidence => palette_color(style.palette, *confidence),
+        };
+
+        for offset in 0..BOX_THICKNESS {
+            let width = (bbox[2].saturating_sub(bbox[0]) + 2 * offset).max(1);
+            let height = (bbox[3].saturating_sub(bbox[1]) + 2 * offset).max(1);
+            let rect = Rect::at(
+                bbox[0] as i32 - offset as i32,
+                bbox[1] as i32 - offset as i32,
+            )
+            .of_size(width, height);
+            draw_hollow_rect_mut(image, rect, color);
+        }
+
+        if let Some(font) = style.font {
+            let label_y = bbox[1] as i32 - BOX_THICKNESS as i32 - LABEL_SCALE as i32;
+            draw_text_mut(
+                image,
+                color,
+                bbox[0] as i32,
+                label_y.max(0),
+                Scale::uniform(LABEL_SCALE),
+                font,
+                &format!("{:.2}", confidence),
+            );
+        }
+    }
+}
+
+fn palette_color(palette: &[(f32, Rgb<u8>)], confidence: f32) -> Rgb<u8> {
+    palette
+        .iter()
+        .find(|(min_confidence, _)| confidence >= *min_confidence)
+        .or(palette.last())
+        .map(|(_, color)| *color)
+        .unwrap_or(PLAIN_COLOR)
+}
@@ -0,0 +1,224 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// `prev_hash` of the first entry of a chain.
+static GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+static SECS_PER_DAY: u64 = 86400;
+/// Bytes read from the end of an existing log at a time while looking for its last line.
+static TAIL_CHUNK: u64 = 4096;
+
+/// Append-only JSONL record of every completed job, separate from the application log. Each line
+/// carries the sha256 of the line before it, so removing or editing a line breaks the chain.
+pub struct AuditLog {
+    pub path: PathBuf,
+    /// The log is rotated before a line would grow it past this size.
+    pub max_bytes: u64,
+    /// Also rotate when the UTC day changes.
+    pub rotate_daily: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: Option<OpenLog>,
+    /// Hash of the last line written, carried over into rotated logs.
+    last_hash: Option<String>,
+}
+
+struct OpenLog {
+    file: File,
+    size: u64,
+    /// UTC day the log was started on.
+    day: u64,
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    /// Unix time in milliseconds.
+    timestamp: u128,
+    id: String,
+    result_name: &'a str,
+    count: usize,
+    confidences: Vec<f32>,
+    prev_hash: &'a str,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf, max_bytes: u64, rotate_daily: bool) -> AuditLog {
+        AuditLog {
+            path,
+            max_bytes,
+            rotate_daily,
+            state: Mutex::new(State {
+                file: None,
+                last_hash: None,
+            }),
+        }
+    }
+
+    /// Append the summary of a completed job, rotating the log first when needed.
+    pub fn record(&self, id: &Uuid, result_name: &str, confidences: Vec<f32>) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        if state.last_hash.is_none() {
+            state.last_hash = Some(last_line_hash(&self.path)?);
+        }
+
+        let prev_hash = state.last_hash.clone().unwrap_or_default();
+        let mut line = serde_json::to_vec(&AuditEntry {
+            timestamp: now.as_millis(),
+            id: id.to_string(),
+            result_name,
+            count: confidences.len(),
+            confidences,
+            prev_hash: &prev_hash,
+        })?;
+        line.push(b'\n');
+
+        let today = now.as_secs() / SECS_PER_DAY;
+        let rotate = match &state.file {
+            Some(log) => {
+                (log.size > 0 && log.size + line.len() as u64 > self.max_bytes)
+                    || (self.rotate_daily && log.day != today)
+            }
+            None => false,
+        };
+        if rotate {
+            state.file = None;
+            let rotated = self.rotated_path(now.as_secs());
+            fs::rename(&self.path, &rotated)?;
+            println!("rotated audit log to {}", rotated.display());
+        }
+        if state.file.is_none() {
+            state.file = Some(self.open(today)?);
+        }
+
+        let log = state.file.as_mut().unwrap();
+        log.file.write_all(&line)?;
+        log.file.sync_data()?;
+        log.size += line.len() as u64;
+        state.last_hash = Some(hex_sha256(&line[..line.len() - 1]));
+        Ok(())
+    }
+
+    /// `{path}.{unix secs}`, with a counter appended when the log was already rotated in the same
+    /// second, so a rotated log is never overwritten.
+    fn rotated_path(&self, secs: u64) -> PathBuf {
+        let rotated = format!("{}.{}", self.path.to_string_lossy(), secs);
+        let mut path = PathBuf::from(&rotated);
+        let mut count = 1;
+        while path.exists() {
+            path = PathBuf::from(format!("{}.{}", rotated, count));
+            count += 1;
+        }
+        path
+    }
+
+    fn open(&self, today: u64) -> io::Result<OpenLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let metadata = file.metadata()?;
+        // an existing log keeps the day it was last written on
+        let day = match metadata.len() {
+            0 => today,
+            _ => {
+                metadata
+                    .modified()?
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+                    / SECS_PER_DAY
+            }
+        };
+        Ok(OpenLog {
+            file,
+            size: metadata.len(),
+            day,
+        })
+    }
+}
+
+/// Hash of the last line of an existing log to continue its chain, a new log starts a new chain.
+/// Only the end of the log is read, growing the tail until it holds the whole last line.
+fn last_line_hash(path: &Path) -> io::Result<String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(GENESIS_HASH.to_string()),
+        Err(err) => return Err(err),
+    };
+    let len = file.metadata()?.len();
+    let mut tail_len = TAIL_CHUNK.min(len);
+    loop {
+        let mut tail = vec![0; tail_len as usize];
+        file.seek(SeekFrom::Start(len - tail_len))?;
+        file.read_exact(&mut tail)?;
+
+        let mut lines = tail
+            .rsplit(|byte| *byte == b'\n')
+            .skip_while(|line| line.is_empty());
+        let last_line = lines.next();
+        // the last line is only whole once the newline before it or the start of the log is read
+        if lines.next().is_some() || tail_len == len {
+            return Ok(last_line.map_or_else(|| GENESIS_HASH.to_string(), hex_sha256));
+        }
+        tail_len = (tail_len * 2).min(len);
+    }
+}
+
+fn hex_sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotations_within_a_second_keep_every_log() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        // every line after the first rotates the log
+        let audit_log = AuditLog::new(dir.join("audit.jsonl"), 1, false);
+        for _ in 0..3 {
+            audit_log
+                .record(&Uuid::new_v4(), "result", vec![0.9])
+                .unwrap();
+        }
+
+        let logs = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(logs, 3);
+    }
+
+    #[test]
+    fn last_line_hash_reads_lines_longer_than_the_tail_chunk() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let long_line = vec![b'x'; TAIL_CHUNK as usize * 3];
+
+        fs::write(&path, [b"first\n", &long_line[..], b"\n"].concat()).unwrap();
+        let with_newline = last_line_hash(&path).unwrap();
+        fs::write(&path, [b"first\n", &long_line[..]].concat()).unwrap();
+        let without_newline = last_line_hash(&path).unwrap();
+        fs::write(&path, b"\n\n").unwrap();
+        let empty = last_line_hash(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(with_newline, hex_sha256(&long_line));
+        assert_eq!(without_newline, hex_sha256(&long_line));
+        assert_eq!(empty, GENESIS_HASH);
+    }
+}
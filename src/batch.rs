@@ -0,0 +1,101 @@
+use std::{fs, io, path::Path};
+
+use image::{io::Reader, ImageFormat};
+
+use crate::{
+    config::Config,
+    detection::{detect_faces, load_image, DetectOptions},
+    error::Error,
+    result_file::{write_result, COMPRESSED_EXTENSION},
+    ultra_predictor::UltraPredictor,
+};
+
+/// Counts reported at the end of a batch run.
+#[derive(Default)]
+pub struct BatchSummary {
+    pub processed: usize,
+    pub failed: usize,
+    /// Files that are not png or jpeg images.
+    pub skipped: usize,
+}
+
+/// Detect faces in every png and jpeg image in `input_dir`, writing the result of `{file}` to
+/// `{output_dir}/{file}.json` in the same envelope as the server's result files, or to
+/// `{file}.json.gz` with `COMPRESS_RESULTS`.
+pub fn run_batch(
+    ultra_predictor: &UltraPredictor,
+    config: &Config,
+    input_dir: &Path,
+    output_dir: &Path,
+) -> io::Result<BatchSummary> {
+    fs::create_dir_all(output_dir)?;
+    let mut paths = fs::read_dir(input_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    let mut summary = BatchSummary::default();
+    for path in paths.iter().filter(|path| path.is_file()) {
+        let format = match sniff_format(path) {
+            Some(format) => format,
+            None => {
+                println!("skipping {}: not a png or jpeg image", path.display());
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let extension = match config.compress_results {
+            true => COMPRESSED_EXTENSION,
+            false => ".json",
+        };
+        let result_path = output_dir.join(format!("{}{}", file_name, extension));
+        match process_image(ultra_predictor, config, path, format, &result_path) {
+            Ok(count) => {
+                println!("{}: {} faces", path.display(), count);
+                summary.processed += 1;
+            }
+            Err(err) => {
+                println!("{}: {}", path.display(), err);
+                summary.failed += 1;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Detect faces in the image at `path` and write the result, returning the number of faces.
+fn process_image(
+    ultra_predictor: &UltraPredictor,
+    config: &Config,
+    path: &Path,
+    format: ImageFormat,
+    result_path: &Path,
+) -> Result<usize, Error> {
+    let image = load_image(path, format, config.decode_limits.clone())?;
+    let options = DetectOptions::default();
+    options.check_size(&image, config.min_image_dimension)?;
+    let mut result = detect_faces(ultra_predictor, &image, &options, config.resize_filter)?;
+    result.assign_ids(&path.file_name().unwrap_or_default().to_string_lossy());
+
+    write_result(
+        result_path,
+        &result,
+        config.pretty_json,
+        config.compress_results,
+    )?;
+    Ok(result.count)
+}
+
+fn sniff_format(path: &Path) -> Option<ImageFormat> {
+    let format = Reader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .format()?;
+    match format {
+        ImageFormat::Png | ImageFormat::Jpeg => Some(format),
+        _ => None,
+    }
+}
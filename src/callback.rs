@@ -0,0 +1,205 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use url::{Host, Url};
+use uuid::Uuid;
+
+/// How long a callback receiver has to answer before the callback is given up.
+static CALLBACK_TIMEOUT_SECS: u64 = 10;
+static PRIVATE_HOST_ERROR: &str = "callback_url must not point to a private or local address";
+
+/// Check a `callback_url` given on enqueue, only absolute http and https urls are called. Hosts
+/// that are loopback, link-local or private addresses are rejected unless listed in
+/// `allowed_hosts`, so clients can not make the server call into its own network.
+pub fn parse_callback_url(
+    callback_url: &str,
+    allowed_hosts: &[String],
+) -> Result<Url, &'static str> {
+    let url = Url::parse(callback_url).map_err(|_| "callback_url is not a valid url")?;
+    let host = match (url.scheme(), url.host()) {
+        ("http" | "https", Some(host)) => host,
+        _ => return Err("callback_url must be an http or https url"),
+    };
+    if is_allowed(&url, allowed_hosts) {
+        return Ok(url);
+    }
+    let public = match host {
+        Host::Ipv4(ip) => is_public(IpAddr::V4(ip)),
+        Host::Ipv6(ip) => is_public(IpAddr::V6(ip)),
+        Host::Domain(domain) => domain != "localhost" && !domain.ends_with(".localhost"),
+    };
+    match public {
+        true => Ok(url),
+        false => Err(PRIVATE_HOST_ERROR),
+    }
+}
+
+/// POST the result of the finished job `id` to its callback, `result` being the content of its
+/// result file, detections or an error. The job id is sent in the `X-Job-Id` header.
+///
+/// Host names are resolved again here and only connected to on public addresses, a name that
+/// passed `parse_callback_url` may resolve to a private address by now. Redirects are not
+/// followed, their target could be any address.
+pub fn send_callback(url: &Url, id: &Uuid, result: &[u8], allowed_hosts: &[String]) {
+    let mut agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(CALLBACK_TIMEOUT_SECS))
+        .redirects(0);
+    if !is_allowed(url, allowed_hosts) {
+        agent = agent.resolver(resolve_public);
+    }
+    let response = agent
+        .build()
+        .post(url.as_str())
+        .set("Content-Type", "application/json")
+        .set("X-Job-Id", &id.to_string())
+        .send_bytes(result);
+    match response {
+        // without following redirects, ureq returns them as responses
+        Ok(response) if (300..400).contains(&response.status()) => println!(
+            "callback for job {} was redirected with {}, redirects are not followed",
+            id,
+            response.status()
+        ),
+        Ok(_) => println!("called back {} for job {}", url, id),
+        Err(err) => println!("callback for job {} failed; {}", id, err),
+    }
+}
+
+/// `CALLBACK_ALLOWED_HOSTS` lists the host of `url`.
+fn is_allowed(url: &Url, allowed_hosts: &[String]) -> bool {
+    match url.host_str() {
+        Some(host) => allowed_hosts.iter().any(|allowed| allowed == host),
+        None => false,
+    }
+}
+
+/// Resolve `address`, `host:port`, to its public addresses only.
+fn resolve_public(address: &str) -> io::Result<Vec<SocketAddr>> {
+    let addresses: Vec<SocketAddr> = address
+        .to_socket_addrs()?
+        .filter(|address| is_public(address.ip()))
+        .collect();
+    match addresses.is_empty() {
+        true => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            PRIVATE_HOST_ERROR,
+        )),
+        false => Ok(addresses),
+    }
+}
+
+/// Whether `ip` is reachable on the internet, not a loopback, link-local (like the
+/// 169.254.169.254 cloud metadata service), private, shared or otherwise reserved address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network", carrier-grade NAT and the reserved 240.0.0.0/4
+        || first == 0
+        || (first == 100 && (64..128).contains(&second))
+        || first >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local fc00::/7 and link-local fe80::/10
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn rejects_local_and_private_callback_hosts() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://localhost:8080/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.5/hook",
+            "http://192.168.1.1/hook",
+            "http://100.64.0.1/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert_eq!(
+                parse_callback_url(url, &[]),
+                Err(PRIVATE_HOST_ERROR),
+                "{}",
+                url
+            );
+        }
+        assert!(parse_callback_url("https://93.184.216.34/hook", &[]).is_ok());
+        assert!(parse_callback_url("https://example.com/hook", &[]).is_ok());
+        assert!(parse_callback_url("ftp://example.com/hook", &[]).is_err());
+    }
+
+    #[test]
+    fn allowed_hosts_may_be_private() {
+        let allowed_hosts = ["10.0.0.5".to_string(), "hooks.internal".to_string()];
+        assert!(parse_callback_url("http://10.0.0.5/hook", &allowed_hosts).is_ok());
+        assert!(parse_callback_url("http://hooks.internal/hook", &allowed_hosts).is_ok());
+        assert!(parse_callback_url("http://10.0.0.6/hook", &allowed_hosts).is_err());
+    }
+
+    #[test]
+    fn callbacks_do_not_follow_redirects() {
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        target.set_nonblocking(true).unwrap();
+        let location = format!("http://{}/elsewhere", target.local_addr().unwrap());
+        let hook = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/hook", hook.local_addr().unwrap())).unwrap();
+        let receiver = thread::spawn(move || {
+            let (mut stream, _) = hook.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            let redirect = format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                location
+            );
+            stream.write_all(redirect.as_bytes()).unwrap();
+        });
+
+        send_callback(&url, &Uuid::new_v4(), b"{}", &["127.0.0.1".to_string()]);
+        receiver.join().unwrap();
+        // a followed redirect would have connected to the target by now
+        assert!(target.accept().is_err());
+    }
+
+    #[test]
+    fn resolves_only_public_addresses() {
+        assert!(resolve_public("127.0.0.1:80").is_err());
+        assert_eq!(
+            resolve_public("93.184.216.34:443").unwrap(),
+            ["93.184.216.34:443".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
@@ -1,23 +1,500 @@
 use dotenv::dotenv;
-use std::{env, path::PathBuf, process};
+use image::{imageops::FilterType, io::Limits, Rgb};
+use rusttype::Font;
+use serde::Serialize;
+use std::{
+    collections::HashMap, env, fmt::Display, fs, path::PathBuf, process, str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    anchors::{DEFAULT_CENTER_VARIANCE, DEFAULT_SIZE_VARIANCE},
+    audit_log::AuditLog,
+    crops::CropStore,
+    detection::Tiling,
+    error::Error,
+    model_source::{cache_file_name, fetch_model, ModelSource, EMBEDDED_MODEL},
+    quality::QualityCheck,
+    quarantine::Quarantine,
+    rate_limiter::RateLimit,
+};
+
+static DEFAULT_MODEL_CACHE_DIR: &str = "./model";
+static DEFAULT_STATSD_PORT: u16 = 8125;
+static DEFAULT_STATSD_PREFIX: &str = "face_detection";
+static DEFAULT_RESULTS_DIR: &str = "./results";
+static DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+static DEFAULT_PORT: u16 = 8082;
+static DEFAULT_GRPC_PORT: u16 = 50051;
+static DEFAULT_ANALYTICS_REFRESH_SECS: u64 = 300;
+static DEFAULT_ANALYTICS_MAX_RESULTS: usize = 100000;
+static DEFAULT_AUDIT_LOG_MAX_MB: u64 = 100;
+static DEFAULT_MAX_NMS_CANDIDATES: usize = 1000;
+static DEFAULT_SCALE_TARGET_LATENCY_MS: u64 = 10000;
+static DEFAULT_PDF_MAX_PAGES: usize = 20;
+static DEFAULT_INFERENCE_FAILURE_LIMIT: usize = 5;
+static DEFAULT_DECODE_AHEAD: usize = 2;
+static DEFAULT_TILE_SIZE: u32 = 640;
+static DEFAULT_TILE_OVERLAP: u32 = 160;
+static DEFAULT_ROW_TOLERANCE: f32 = 0.5;
+static DEFAULT_INFERENCE_TIMEOUT_MS: u64 = 30000;
+static DEFAULT_REQUEST_TIMEOUT_MS: u64 = 60000;
+static DEFAULT_CONFIDENCE_THRESHOLD: f32 = 0.5;
+static DEFAULT_IDEMPOTENCY_WINDOW_SECS: u64 = 3600;
+static DEFAULT_JPEG_QUALITY: u8 = 85;
+static DEFAULT_JOB_TTL_SECS: u64 = 3600;
+static DEFAULT_PRIORITY_AGING_MS: u64 = 60000;
+static DEFAULT_WORKER_MAX_RESTARTS: u32 = 5;
+static DEFAULT_WORKER_RESTART_BACKOFF_MS: u64 = 1000;
+static DEFAULT_QUARANTINE_MAX_FILES: usize = 100;
+static DEFAULT_QUARANTINE_TTL_SECS: u64 = 86400;
+static DEFAULT_ARCHIVE_MAX_FILES: usize = 10000;
+static DEFAULT_ARCHIVE_TTL_SECS: u64 = 30 * 86400;
+static DEFAULT_MIN_IMAGE_DIMENSION: u32 = 10;
+static DEFAULT_DECODE_MAX_ALLOC_MB: u64 = 512;
+static DEFAULT_MIN_BOX_ASPECT_RATIO: f32 = 0.2;
+static DEFAULT_MAX_BOX_ASPECT_RATIO: f32 = 5.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ResultsService {
+    /// Serve `./results` as a static directory.
+    Static,
+    /// Serve results through the `/result/{id}` handler, which only reads `{id}.json` for a valid
+    /// result name.
+    Handler,
+}
+
+impl ResultsService {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResultsService::Static => "static",
+            ResultsService::Handler => "handler",
+        }
+    }
+}
+
+/// How queued result files in `./results` are named.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ResultNaming {
+    /// `{job id}.json`
+    Uuid,
+    /// `{sha256 of the upload}.json`, identical uploads share a result file.
+    ContentHash,
+    /// `{name}.json` with the `?name=` of the upload, the job id when it is missing.
+    ClientName,
+}
+
+impl ResultNaming {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResultNaming::Uuid => "uuid",
+            ResultNaming::ContentHash => "content_hash",
+            ResultNaming::ClientName => "client_name",
+        }
+    }
+}
+
+/// Which completed queued jobs get a result file.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ResultWriteMode {
+    Always,
+    /// Only results with a face of at least `RESULT_MIN_CONFIDENCE`, failed jobs still get their
+    /// error result.
+    FacesOnly,
+}
+
+impl ResultWriteMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResultWriteMode::Always => "always",
+            ResultWriteMode::FacesOnly => "faces_only",
+        }
+    }
+}
+
+/// Execution provider the onnx session runs on, CPU is used when it is not available.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExecutionProviderKind {
+    Cpu,
+    /// Apple Neural Engine or GPU on macOS, needs onnxruntime built with CoreML.
+    CoreML,
+    /// AMD GPUs on Linux, needs onnxruntime built with ROCm.
+    ROCm,
+    /// Any DirectX 12 GPU on Windows, needs onnxruntime built with DirectML.
+    DirectML,
+}
+
+impl ExecutionProviderKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExecutionProviderKind::Cpu => "cpu",
+            ExecutionProviderKind::CoreML => "coreml",
+            ExecutionProviderKind::ROCm => "rocm",
+            ExecutionProviderKind::DirectML => "directml",
+        }
+    }
+}
+
+/// How non-maximum-suppression turns overlapping candidates into one box.
+#[derive(Clone, Copy, PartialEq)]
+pub enum NmsMode {
+    /// Keep the most confident box of each cluster.
+    Hard,
+    /// Average the boxes of each cluster weighted by confidence, often localizing tighter.
+    WeightedFusion,
+}
+
+impl NmsMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NmsMode::Hard => "hard",
+            NmsMode::WeightedFusion => "weighted_fusion",
+        }
+    }
+}
+
+/// Order of the dimensions of the model input.
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputLayout {
+    /// `[1, 3, height, width]`, as exported from PyTorch.
+    Nchw,
+    /// `[1, height, width, 3]`, as exported from TensorFlow.
+    Nhwc,
+}
+
+impl InputLayout {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputLayout::Nchw => "nchw",
+            InputLayout::Nhwc => "nhwc",
+        }
+    }
+}
+
+/// Order of the color channels the model expects.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChannelOrder {
+    Rgb,
+    /// Blue first, as expected by models trained on OpenCV or Caffe input.
+    Bgr,
+}
+
+impl ChannelOrder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelOrder::Rgb => "rgb",
+            ChannelOrder::Bgr => "bgr",
+        }
+    }
+}
+
+/// What happens to a result whose most confident face is below `MIN_ACCEPT_CONFIDENCE`, or whose
+/// image fails the quality pre-check with `QUALITY_MODE`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AcceptMode {
+    /// Mark the result `accepted: false` with a reason, keeping its detections.
+    Flag,
+    /// Also drop the detections, `/detect` answers with a 422.
+    Reject,
+}
+
+impl AcceptMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AcceptMode::Flag => "flag",
+            AcceptMode::Reject => "reject",
+        }
+    }
+}
+
+/// What `/queue` does with a new upload when the queue is full.
+#[derive(Clone, Copy, PartialEq)]
+pub enum QueueFullPolicy {
+    /// Reject the new upload with a 503.
+    Reject,
+    /// Drop the oldest queued job to make room, for realtime feeds where stale frames are worthless.
+    DropOldest,
+}
+
+impl QueueFullPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueueFullPolicy::Reject => "reject",
+            QueueFullPolicy::DropOldest => "drop_oldest",
+        }
+    }
+}
 
 pub struct Config {
-    pub ultra_model_path: PathBuf,
+    pub model_source: ModelSource,
+    /// Model id added to every result, the model file name unless `MODEL_ID` is set.
+    pub model_id: String,
+    /// Model version added to every result, the model's sha256 unless `MODEL_VERSION` is set.
+    pub model_version: String,
+    /// Server instance added to every result and `/health`, the hostname unless `INSTANCE_ID` is
+    /// set.
+    pub instance_id: String,
+    /// Intra-op threads, the threads a single operator such as a convolution is split over.
     pub ultra_threads: i16,
+    /// Inter-op threads running independent branches of the graph in parallel, `None` runs the
+    /// graph sequentially.
+    pub ultra_inter_threads: Option<i16>,
+    pub results_service: ResultsService,
+    pub resize_filter: FilterType,
+    pub inference_timeout: Duration,
+    pub pretty_json: bool,
+    /// Gzip result files on disk as `{name}.json.gz`.
+    pub compress_results: bool,
+    pub confidence_threshold: f32,
+    pub report_confidence: f32,
+    /// Limit for uploads per API key or client IP, `None` disables rate limiting.
+    pub rate_limit: Option<RateLimit>,
+    pub rate_limit_keys: HashMap<String, RateLimit>,
+    /// How long an `Idempotency-Key` keeps returning the job it created.
+    pub idempotency_window: Duration,
+    /// `(minimum confidence, color)` pairs used to color annotated boxes by confidence.
+    pub annotation_palette: Vec<(f32, Rgb<u8>)>,
+    /// Font used to label annotated boxes with their confidence.
+    pub annotation_font: Option<Font<'static>>,
+    /// Quality (1-100) of jpeg encoded image responses.
+    pub jpeg_quality: u8,
+    /// How long the status of a finished job is kept.
+    pub job_ttl: Duration,
+    /// Wait after which a queued job is raised a priority level, `None` never raises it.
+    pub priority_aging: Option<Duration>,
+    /// Jobs a client may have queued or processing at once, `None` leaves clients unbounded.
+    pub queue_quota: Option<usize>,
+    /// Times the queue worker is restarted after panicking or exiting before it is left dead.
+    pub worker_max_restarts: u32,
+    /// Wait before the first restart of the queue worker, doubled for every further one.
+    pub worker_restart_backoff: Duration,
+    /// Where uploads that failed to decode are kept for inspection, `None` deletes them.
+    pub quarantine: Option<Quarantine>,
+    /// Where processed uploads are kept for auditing, `None` deletes them.
+    pub archive: Option<Quarantine>,
+    /// Where face crops of completed jobs are kept for `job_ttl`, `None` keeps no crops.
+    pub crops: Option<CropStore>,
+    /// Images narrower or lower than this many pixels are rejected instead of detected in.
+    pub min_image_dimension: u32,
+    /// Bounds for decoding uploads, so a decompression bomb fails instead of exhausting memory.
+    pub decode_limits: Limits,
+    pub queue_full_policy: QueueFullPolicy,
+    pub execution_provider: ExecutionProviderKind,
+    pub result_naming: ResultNaming,
+    pub result_write_mode: ResultWriteMode,
+    /// Confidence a face needs for `ResultWriteMode::FacesOnly` to write the result.
+    pub result_min_confidence: f32,
+    /// Upper bound on a whole request, including receiving the upload, decoding and inference.
+    pub request_timeout: Duration,
+    /// Run non-maximum-suppression per face class instead of across all classes.
+    pub nms_per_class: bool,
+    /// Number of HTTP worker threads, one per physical core when `None`.
+    pub http_workers: Option<usize>,
+    /// Concurrent connections per HTTP worker, actix' default of 25k when `None`.
+    pub max_connections: Option<usize>,
+    /// Decode model box outputs as regressions relative to prior boxes, with the center and size
+    /// variances, instead of reading them as corners.
+    pub anchor_decoding: Option<(f32, f32)>,
+    /// Maximum number of queued items the worker works off per pass, unbounded when `None`.
+    pub drain_limit: Option<usize>,
+    /// Queued uploads the worker decodes ahead of the one in inference, 0 decodes and infers one
+    /// upload after the other.
+    pub decode_ahead: usize,
+    /// Tiles of `?tiled=true` detection.
+    pub tiling: Tiling,
+    /// Row tolerance of `?sort=spatial`, in box heights.
+    pub row_tolerance: f32,
+    pub prescale: bool,
+    /// `X-Api-Key` unlocking the `/admin` endpoints, which are disabled when `None`.
+    pub admin_api_key: Option<String>,
+    /// Directory `/queue` uploads are buffered in, the system temp directory when `None`.
+    pub upload_dir: Option<PathBuf>,
+    /// `(min, max)` width:height of reported boxes.
+    pub box_aspect_ratio: (f32, f32),
+    /// Where queued results are written and served from.
+    pub results_dir: PathBuf,
+    pub bind_address: String,
+    pub port: u16,
+    /// Inferences allowed to run at once across all paths, unbounded when `None`.
+    pub max_concurrent_inference: Option<usize>,
+    pub nms_mode: NmsMode,
+    /// Only the most confident candidates go into non-maximum-suppression, bounding its cost.
+    pub max_nms_candidates: usize,
+    /// Layout of the model input, `None` detects it from the model's input shape.
+    pub input_layout: Option<InputLayout>,
+    pub channel_order: ChannelOrder,
+    /// Consecutive inference failures after which the session is rebuilt, and the server marked
+    /// unhealthy when that does not help.
+    pub inference_failure_limit: usize,
+    /// Results without a face of at least this confidence are not accepted, `None` accepts all
+    /// results and leaves `accepted` out.
+    pub min_accept_confidence: Option<f32>,
+    pub accept_mode: AcceptMode,
+    /// Boxes touching the image border are grown by this fraction of their size on every side,
+    /// `None` reports boxes as the model produced them.
+    pub edge_padding: Option<f32>,
+    /// Brightness and contrast pre-check, `None` when neither `MIN_BRIGHTNESS` nor
+    /// `MIN_CONTRAST` is set.
+    pub quality_check: Option<QualityCheck>,
+    /// Largest side of the thumbnail of the image embedded in results, `None` embeds none.
+    pub thumbnail_max_size: Option<u32>,
+    /// Faces per megapixel from which a result is `crowded`, `None` adds no density to results.
+    pub crowd_density_threshold: Option<f32>,
+    /// Serve the gRPC interface on `grpc_port`, needs the `grpc` feature.
+    pub grpc_enabled: bool,
+    pub grpc_port: u16,
+    /// How often `/analytics` rescans the results directory.
+    pub analytics_refresh: Duration,
+    /// Result files scanned per refresh at most, bounding the time and IO of a scan.
+    pub analytics_max_results: usize,
+    /// Append-only record of completed jobs, `None` keeps no audit log.
+    pub audit_log: Option<AuditLog>,
+    /// Time a queued job may wait for the workers `/scale` recommends.
+    pub scale_target_latency: Duration,
+    /// Pages of a pdf `/detect/pdf` renders and detects in, later pages are ignored.
+    pub pdf_max_pages: usize,
+    /// Add `X-Inference-Time-Ms` and `X-Queue-Wait-Ms` headers to synchronous detections.
+    pub timing_headers: bool,
+    /// Serve the upload page for manual testing on `/`.
+    pub test_page: bool,
+    /// Allow `/detect?raw=true`, whose response holds thousands of candidates.
+    pub debug_raw_candidates: bool,
+    /// `host:port` of the StatsD agent metrics are pushed to, `None` pushes no metrics.
+    pub statsd_address: Option<String>,
+    pub statsd_prefix: String,
+    /// DogStatsD tags added to every metric, i.e. `env:prod,region:eu`.
+    pub statsd_tags: Option<String>,
+    /// Callback hosts called even when they are private or local addresses.
+    pub callback_allowed_hosts: Vec<String>,
+}
+
+/// The resolved `Config` as reported by `/admin/config`, in the units and spellings of the env
+/// variables. API keys are left out, only the number of keys with their own rate limit is shown.
+#[derive(Serialize)]
+pub struct EffectiveConfig {
+    pub model_source: String,
+    pub model_id: String,
+    pub model_version: String,
+    pub instance_id: String,
+    pub ultra_threads: i16,
+    pub ultra_inter_threads: Option<i16>,
+    pub results_service: &'static str,
+    pub resize_filter: &'static str,
+    pub inference_timeout_ms: u128,
+    pub pretty_json: bool,
+    pub compress_results: bool,
+    pub confidence_threshold: f32,
+    pub report_confidence: f32,
+    pub rate_limit: Option<RateLimit>,
+    pub rate_limit_key_count: usize,
+    pub idempotency_window_secs: u64,
+    pub annotation_palette: Vec<(f32, String)>,
+    pub annotation_font: bool,
+    pub jpeg_quality: u8,
+    pub job_ttl_secs: u64,
+    pub priority_aging_ms: Option<u128>,
+    pub queue_quota: Option<usize>,
+    pub worker_max_restarts: u32,
+    pub worker_restart_backoff_ms: u128,
+    pub quarantine_dir: Option<PathBuf>,
+    pub quarantine_max_files: Option<usize>,
+    pub quarantine_ttl_secs: Option<u64>,
+    pub archive_dir: Option<PathBuf>,
+    pub archive_max_files: Option<usize>,
+    pub archive_ttl_secs: Option<u64>,
+    pub crops_dir: Option<PathBuf>,
+    pub min_image_dimension: u32,
+    pub decode_max_width: Option<u32>,
+    pub decode_max_height: Option<u32>,
+    pub decode_max_alloc_mb: Option<u64>,
+    pub queue_full_policy: &'static str,
+    pub execution_provider: &'static str,
+    pub result_naming: &'static str,
+    pub result_write_mode: &'static str,
+    pub result_min_confidence: f32,
+    pub request_timeout_ms: u128,
+    pub nms_per_class: bool,
+    pub http_workers: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub anchor_center_variance: Option<f32>,
+    pub anchor_size_variance: Option<f32>,
+    pub worker_batch_size: Option<usize>,
+    pub decode_ahead: usize,
+    pub tile_size: u32,
+    pub tile_overlap: u32,
+    pub row_tolerance: f32,
+    pub prescale: bool,
+    pub upload_dir: Option<PathBuf>,
+    pub min_box_aspect_ratio: f32,
+    pub max_box_aspect_ratio: f32,
+    pub results_dir: PathBuf,
+    pub bind_address: String,
+    pub port: u16,
+    pub max_concurrent_inference: Option<usize>,
+    pub nms_mode: &'static str,
+    pub input_layout: &'static str,
+    pub channel_order: &'static str,
+    pub inference_failure_limit: usize,
+    pub min_accept_confidence: Option<f32>,
+    pub accept_mode: &'static str,
+    pub edge_padding: Option<f32>,
+    pub min_brightness: Option<f32>,
+    pub min_contrast: Option<f32>,
+    pub quality_mode: Option<&'static str>,
+    pub thumbnail_max_size: Option<u32>,
+    pub crowd_density_threshold: Option<f32>,
+    pub max_nms_candidates: usize,
+    pub grpc_enabled: bool,
+    pub grpc_port: u16,
+    pub analytics_refresh_secs: u64,
+    pub analytics_max_results: usize,
+    pub audit_log_path: Option<PathBuf>,
+    pub audit_log_max_mb: Option<u64>,
+    pub audit_log_rotate_daily: Option<bool>,
+    pub scale_target_latency_ms: u128,
+    pub pdf_max_pages: usize,
+    pub timing_headers: bool,
+    pub test_page: bool,
+    pub debug_raw_candidates: bool,
+    pub statsd_address: Option<String>,
+    pub statsd_prefix: String,
+    pub statsd_tags: Option<String>,
+    pub callback_allowed_hosts: Vec<String>,
 }
 
 impl Config {
     pub fn new() -> Config {
         dotenv().ok();
-        let ultra_model_path = env::var("ULTRA_MODEL_PATH").unwrap_or_else(|err| {
-            println!("Unable to get ULTRA_MODEL_PATH env variable: {}", err);
-            process::exit(1);
-        });
-        let ultra_model_path = PathBuf::from(&ultra_model_path);
-        if !ultra_model_path.exists() {
-            println!("Unable to find ULTRA_MODEL_PATH");
-            process::exit(1);
-        }
+        let model_source = match env::var("ULTRA_MODEL_URL") {
+            Ok(url) => {
+                // ULTRA_MODEL_PATH is where the downloaded model is cached when a url is given
+                let cache_path = match env::var("ULTRA_MODEL_PATH") {
+                    Ok(path) => PathBuf::from(path),
+                    Err(_) => PathBuf::from(DEFAULT_MODEL_CACHE_DIR).join(cache_file_name(&url)),
+                };
+                let model_path = fetch_model(&url, &cache_path).unwrap_or_else(|err| {
+                    println!("Unable to download ULTRA_MODEL_URL: {}", err);
+                    process::exit(1)
+                });
+                ModelSource::File(model_path)
+            }
+            Err(_) => match (env::var("ULTRA_MODEL_PATH"), EMBEDDED_MODEL) {
+                // ULTRA_MODEL_PATH still overrides a model built into the binary
+                (Err(_), Some(model_bytes)) => ModelSource::Memory(model_bytes),
+                (ultra_model_path, _) => {
+                    let ultra_model_path = ultra_model_path.unwrap_or_else(|err| {
+                        println!("Unable to get ULTRA_MODEL_PATH env variable: {}", err);
+                        process::exit(1);
+                    });
+                    let ultra_model_path = PathBuf::from(&ultra_model_path);
+                    if !ultra_model_path.exists() {
+                        println!("Unable to find ULTRA_MODEL_PATH");
+                        process::exit(1);
+                    }
+                    ModelSource::File(ultra_model_path)
+                }
+            },
+        };
 
         let ultra_threads: i16 = env::var("ULTRA_THREADS")
             .unwrap_or_else(|err| {
@@ -29,10 +506,787 @@ impl Config {
                 println!("Unable to parse ULTRA_THREADS env variable: {}", err);
                 process::exit(1)
             });
+        // the two pools share the cores, ULTRA_THREADS + ULTRA_INTER_THREADS should stay at or
+        // below the core count
+        let ultra_inter_threads: Option<i16> = parse_env("ULTRA_INTER_THREADS");
+        if ultra_threads < 1 || ultra_inter_threads.is_some_and(|threads| threads < 1) {
+            println!("ULTRA_THREADS and ULTRA_INTER_THREADS must be at least 1");
+            process::exit(1);
+        }
+
+        let results_service = match env::var("RESULTS_SERVICE").as_deref() {
+            Err(_) | Ok("static") => ResultsService::Static,
+            Ok("handler") => ResultsService::Handler,
+            Ok(other) => {
+                println!("Unable to parse RESULTS_SERVICE env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        // Nearest is the fastest, Lanczos3 the sharpest but slowest. Sharper downscaling can help
+        // detecting small faces, so changing the filter can subtly change the detections.
+        let resize_filter = match env::var("RESIZE_FILTER").as_deref() {
+            Err(_) | Ok("triangle") => FilterType::Triangle,
+            Ok("nearest") => FilterType::Nearest,
+            Ok("catmullrom") => FilterType::CatmullRom,
+            Ok("gaussian") => FilterType::Gaussian,
+            Ok("lanczos3") => FilterType::Lanczos3,
+            Ok(other) => {
+                println!("Unable to parse RESIZE_FILTER env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        let inference_timeout = Duration::from_millis(parse_optional_env(
+            "INFERENCE_TIMEOUT_MS",
+            DEFAULT_INFERENCE_TIMEOUT_MS,
+        ));
+
+        // compact JSON by default, pretty-printing is meant for inspecting results during development
+        let pretty_json = parse_optional_env("PRETTY_JSON", false);
+
+        // the static results service would serve the compressed files as they are, only the
+        // handler can decompress them for clients that do not accept gzip
+        let compress_results = parse_optional_env("COMPRESS_RESULTS", false);
+        if compress_results && results_service == ResultsService::Static {
+            println!("COMPRESS_RESULTS needs RESULTS_SERVICE=handler");
+            process::exit(1)
+        }
+
+        // `CONFIDENCE_THRESHOLD` filters candidates before non-maximum-suppression, a lower value
+        // lets NMS consider more candidates. `REPORT_CONFIDENCE` filters the boxes NMS selected.
+        let confidence_threshold =
+            parse_optional_env("CONFIDENCE_THRESHOLD", DEFAULT_CONFIDENCE_THRESHOLD);
+        let report_confidence = parse_optional_env("REPORT_CONFIDENCE", confidence_threshold);
+
+        let rate_limit = parse_env("RATE_LIMIT_RPS").map(|requests_per_second: f64| RateLimit {
+            requests_per_second,
+            burst: parse_optional_env("RATE_LIMIT_BURST", f64::max(requests_per_second, 1.0)),
+        });
+        if let Some(limit) = rate_limit {
+            if limit.requests_per_second <= 0.0 || limit.burst < 1.0 {
+                println!("RATE_LIMIT_RPS must be positive and RATE_LIMIT_BURST at least 1");
+                process::exit(1);
+            }
+        }
+        let rate_limit_keys = match env::var("RATE_LIMIT_KEYS") {
+            Ok(keys) => parse_rate_limit_keys(&keys).unwrap_or_else(|err| {
+                println!("Unable to parse RATE_LIMIT_KEYS env variable: {}", err);
+                process::exit(1)
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        let idempotency_window = Duration::from_secs(parse_optional_env(
+            "IDEMPOTENCY_WINDOW_SECS",
+            DEFAULT_IDEMPOTENCY_WINDOW_SECS,
+        ));
+
+        let annotation_palette = match env::var("ANNOTATION_PALETTE") {
+            Ok(palette) => parse_palette(&palette).unwrap_or_else(|err| {
+                println!("Unable to parse ANNOTATION_PALETTE env variable: {}", err);
+                process::exit(1)
+            }),
+            Err(_) => vec![
+                (0.9, Rgb([0, 255, 0])),
+                (0.7, Rgb([255, 255, 0])),
+                (0.0, Rgb([255, 0, 0])),
+            ],
+        };
+
+        let annotation_font = env::var("ANNOTATION_FONT_PATH").ok().map(|font_path| {
+            let font_bytes = fs::read(&font_path).unwrap_or_else(|err| {
+                println!("Unable to read ANNOTATION_FONT_PATH: {}", err);
+                process::exit(1)
+            });
+            Font::try_from_vec(font_bytes).unwrap_or_else(|| {
+                println!("Unable to parse font at ANNOTATION_FONT_PATH");
+                process::exit(1)
+            })
+        });
+
+        let jpeg_quality = parse_optional_env("JPEG_QUALITY", DEFAULT_JPEG_QUALITY);
+        if !(1..=100).contains(&jpeg_quality) {
+            println!("JPEG_QUALITY must be between 1 and 100");
+            process::exit(1);
+        }
+
+        let job_ttl = Duration::from_secs(parse_optional_env("JOB_TTL_SECS", DEFAULT_JOB_TTL_SECS));
+
+        // 0 processes jobs strictly by priority, low priority jobs may then wait forever
+        let priority_aging =
+            match parse_optional_env("PRIORITY_AGING_MS", DEFAULT_PRIORITY_AGING_MS) {
+                0 => None,
+                aging => Some(Duration::from_millis(aging)),
+            };
+
+        let queue_quota: Option<usize> = parse_env("QUEUE_QUOTA");
+        if queue_quota == Some(0) {
+            println!("QUEUE_QUOTA must be at least 1");
+            process::exit(1);
+        }
+
+        let worker_max_restarts =
+            parse_optional_env("WORKER_MAX_RESTARTS", DEFAULT_WORKER_MAX_RESTARTS);
+        let worker_restart_backoff = Duration::from_millis(parse_optional_env(
+            "WORKER_RESTART_BACKOFF_MS",
+            DEFAULT_WORKER_RESTART_BACKOFF_MS,
+        ));
+
+        let quarantine = env::var("QUARANTINE_DIR").ok().map(|dir| Quarantine {
+            dir: PathBuf::from(dir),
+            max_files: parse_optional_env("QUARANTINE_MAX_FILES", DEFAULT_QUARANTINE_MAX_FILES),
+            ttl: Duration::from_secs(parse_optional_env(
+                "QUARANTINE_TTL_SECS",
+                DEFAULT_QUARANTINE_TTL_SECS,
+            )),
+        });
+        if let Some(Quarantine { max_files: 0, .. }) = quarantine {
+            println!("QUARANTINE_MAX_FILES must be at least 1");
+            process::exit(1);
+        }
+
+        let archive = env::var("ARCHIVE_DIR").ok().map(|dir| Quarantine {
+            dir: PathBuf::from(dir),
+            max_files: parse_optional_env("ARCHIVE_MAX_FILES", DEFAULT_ARCHIVE_MAX_FILES),
+            ttl: Duration::from_secs(parse_optional_env(
+                "ARCHIVE_TTL_SECS",
+                DEFAULT_ARCHIVE_TTL_SECS,
+            )),
+        });
+        if let Some(Quarantine { max_files: 0, .. }) = archive {
+            println!("ARCHIVE_MAX_FILES must be at least 1");
+            process::exit(1);
+        }
+
+        let crops = env::var("CROPS_DIR").ok().map(|dir| CropStore {
+            dir: PathBuf::from(dir),
+            ttl: job_ttl,
+        });
+
+        let min_image_dimension =
+            parse_optional_env("MIN_IMAGE_DIMENSION", DEFAULT_MIN_IMAGE_DIMENSION);
+
+        let mut decode_limits = Limits::default();
+        decode_limits.max_image_width = parse_env("DECODE_MAX_WIDTH");
+        decode_limits.max_image_height = parse_env("DECODE_MAX_HEIGHT");
+        decode_limits.max_alloc = Some(
+            parse_optional_env("DECODE_MAX_ALLOC_MB", DEFAULT_DECODE_MAX_ALLOC_MB) * 1024 * 1024,
+        );
+
+        let queue_full_policy = match env::var("QUEUE_FULL_POLICY").as_deref() {
+            Err(_) | Ok("reject") => QueueFullPolicy::Reject,
+            Ok("drop_oldest") => QueueFullPolicy::DropOldest,
+            Ok(other) => {
+                println!("Unable to parse QUEUE_FULL_POLICY env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        let execution_provider = match env::var("EXECUTION_PROVIDER").as_deref() {
+            Err(_) | Ok("cpu") => ExecutionProviderKind::Cpu,
+            Ok("coreml") => ExecutionProviderKind::CoreML,
+            Ok("rocm") => ExecutionProviderKind::ROCm,
+            Ok("directml") => ExecutionProviderKind::DirectML,
+            Ok(other) => {
+                println!("Unable to parse EXECUTION_PROVIDER env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        let result_naming = match env::var("RESULT_NAMING").as_deref() {
+            Err(_) | Ok("uuid") => ResultNaming::Uuid,
+            Ok("content_hash") => ResultNaming::ContentHash,
+            Ok("client_name") => ResultNaming::ClientName,
+            Ok(other) => {
+                println!("Unable to parse RESULT_NAMING env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        let result_write_mode = match env::var("RESULT_WRITE_MODE").as_deref() {
+            Err(_) | Ok("always") => ResultWriteMode::Always,
+            Ok("faces_only") => ResultWriteMode::FacesOnly,
+            Ok(other) => {
+                println!("Unable to parse RESULT_WRITE_MODE env variable: {}", other);
+                process::exit(1)
+            }
+        };
+        // any reported face is enough by default
+        let result_min_confidence: f32 = parse_optional_env("RESULT_MIN_CONFIDENCE", 0.0);
+        if !(0.0..=1.0).contains(&result_min_confidence) {
+            println!("RESULT_MIN_CONFIDENCE must be between 0 and 1");
+            process::exit(1);
+        }
+
+        let request_timeout = Duration::from_millis(parse_optional_env(
+            "REQUEST_TIMEOUT_MS",
+            DEFAULT_REQUEST_TIMEOUT_MS,
+        ));
+
+        // a no-op for the single face class of the ultra-light model
+        let nms_per_class = parse_optional_env("NMS_PER_CLASS", false);
+
+        let nms_mode = match env::var("NMS_MODE").as_deref() {
+            Err(_) | Ok("hard") => NmsMode::Hard,
+            Ok("weighted_fusion") => NmsMode::WeightedFusion,
+            Ok(other) => {
+                println!("Unable to parse NMS_MODE env variable: {}", other);
+                process::exit(1)
+            }
+        };
+        let max_nms_candidates =
+            parse_optional_env("MAX_NMS_CANDIDATES", DEFAULT_MAX_NMS_CANDIDATES);
+        if max_nms_candidates == 0 {
+            println!("MAX_NMS_CANDIDATES must be at least 1");
+            process::exit(1);
+        }
+
+        let input_layout = match env::var("INPUT_LAYOUT").as_deref() {
+            Err(_) | Ok("auto") => None,
+            Ok("nchw") => Some(InputLayout::Nchw),
+            Ok("nhwc") => Some(InputLayout::Nhwc),
+            Ok(other) => {
+                println!("Unable to parse INPUT_LAYOUT env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        let channel_order = match env::var("CHANNEL_ORDER").as_deref() {
+            Err(_) | Ok("rgb") => ChannelOrder::Rgb,
+            Ok("bgr") => ChannelOrder::Bgr,
+            Ok(other) => {
+                println!("Unable to parse CHANNEL_ORDER env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        let inference_failure_limit =
+            parse_optional_env("INFERENCE_FAILURE_LIMIT", DEFAULT_INFERENCE_FAILURE_LIMIT);
+        if inference_failure_limit == 0 {
+            println!("INFERENCE_FAILURE_LIMIT must be at least 1");
+            process::exit(1);
+        }
+
+        let min_accept_confidence: Option<f32> = parse_env("MIN_ACCEPT_CONFIDENCE");
+        if min_accept_confidence.is_some_and(|confidence| !(0.0..=1.0).contains(&confidence)) {
+            println!("MIN_ACCEPT_CONFIDENCE must be between 0 and 1");
+            process::exit(1);
+        }
+        let accept_mode = match env::var("ACCEPT_MODE").as_deref() {
+            Err(_) | Ok("flag") => AcceptMode::Flag,
+            Ok("reject") => AcceptMode::Reject,
+            Ok(other) => {
+                println!("Unable to parse ACCEPT_MODE env variable: {}", other);
+                process::exit(1)
+            }
+        };
+
+        let edge_padding: Option<f32> = parse_env("EDGE_PADDING");
+        if edge_padding.is_some_and(|padding| !(0.0..=1.0).contains(&padding)) {
+            println!("EDGE_PADDING must be between 0 and 1");
+            process::exit(1);
+        }
+
+        let min_brightness: Option<f32> = parse_env("MIN_BRIGHTNESS");
+        if min_brightness.is_some_and(|brightness| !(0.0..=255.0).contains(&brightness)) {
+            println!("MIN_BRIGHTNESS must be between 0 and 255");
+            process::exit(1);
+        }
+        let min_contrast: Option<f32> = parse_env("MIN_CONTRAST");
+        if min_contrast.is_some_and(|contrast| !(0.0..=127.5).contains(&contrast)) {
+            println!("MIN_CONTRAST must be between 0 and 127.5");
+            process::exit(1);
+        }
+        let quality_mode = match env::var("QUALITY_MODE").as_deref() {
+            Err(_) | Ok("flag") => AcceptMode::Flag,
+            Ok("reject") => AcceptMode::Reject,
+            Ok(other) => {
+                println!("Unable to parse QUALITY_MODE env variable: {}", other);
+                process::exit(1)
+            }
+        };
+        let quality_check =
+            (min_brightness.is_some() || min_contrast.is_some()).then_some(QualityCheck {
+                min_brightness,
+                min_contrast,
+                mode: quality_mode,
+            });
+
+        // thumbnails copy image content into the results, off unless asked for like ARCHIVE_DIR
+        let thumbnail_max_size: Option<u32> = parse_env("THUMBNAIL_MAX_SIZE");
+        if thumbnail_max_size == Some(0) {
+            println!("THUMBNAIL_MAX_SIZE must be at least 1");
+            process::exit(1);
+        }
+
+        let crowd_density_threshold: Option<f32> = parse_env("CROWD_DENSITY_THRESHOLD");
+        if crowd_density_threshold
+            .is_some_and(|threshold| !threshold.is_finite() || threshold <= 0.0)
+        {
+            println!("CROWD_DENSITY_THRESHOLD must be above 0");
+            process::exit(1);
+        }
+
+        // workers only parse uploads and wait on the blocking pool, so keeping
+        // HTTP_WORKERS + ULTRA_THREADS at or below the core count avoids oversubscription
+        let http_workers = parse_env("HTTP_WORKERS");
+        let max_connections = parse_env("MAX_CONNECTIONS");
+        if http_workers == Some(0) || max_connections == Some(0) {
+            println!("HTTP_WORKERS and MAX_CONNECTIONS must be at least 1");
+            process::exit(1);
+        }
+
+        let anchor_decoding = match parse_optional_env("ANCHOR_DECODING", false) {
+            true => Some((
+                parse_optional_env("ANCHOR_CENTER_VARIANCE", DEFAULT_CENTER_VARIANCE),
+                parse_optional_env("ANCHOR_SIZE_VARIANCE", DEFAULT_SIZE_VARIANCE),
+            )),
+            false => None,
+        };
+
+        let drain_limit = parse_env("WORKER_BATCH_SIZE");
+        if drain_limit == Some(0) {
+            println!("WORKER_BATCH_SIZE must be at least 1");
+            process::exit(1);
+        }
+        let decode_ahead = parse_optional_env("DECODE_AHEAD", DEFAULT_DECODE_AHEAD);
+
+        let tiling = Tiling {
+            size: parse_optional_env("TILE_SIZE", DEFAULT_TILE_SIZE),
+            overlap: parse_optional_env("TILE_OVERLAP", DEFAULT_TILE_OVERLAP),
+        };
+        if tiling.overlap >= Tiling::tile_height(tiling.size) {
+            println!("TILE_OVERLAP must be smaller than the tile height, 3/4 of TILE_SIZE");
+            process::exit(1);
+        }
+        let row_tolerance = parse_optional_env("ROW_TOLERANCE", DEFAULT_ROW_TOLERANCE);
+        if row_tolerance.is_nan() || row_tolerance < 0.0 {
+            println!("ROW_TOLERANCE must not be negative");
+            process::exit(1);
+        }
+
+        // a box filtered prescale can shift boxes by a pixel or so compared to a single resize
+        let prescale = parse_optional_env("PRESCALE", false);
+
+        let admin_api_key = env::var("ADMIN_API_KEY").ok().filter(|key| !key.is_empty());
+
+        // uploads are written to a temp file and read back when decoding, a tmpfs such as
+        // /dev/shm keeps both in memory
+        let upload_dir = env::var("UPLOAD_DIR").ok().map(PathBuf::from);
+        if let Some(upload_dir) = &upload_dir {
+            fs::create_dir_all(upload_dir).unwrap_or_else(|err| {
+                println!("Unable to create UPLOAD_DIR: {}", err);
+                process::exit(1)
+            });
+        }
+
+        // faces are roughly square, the permissive defaults only drop extremely wide or tall boxes
+        let box_aspect_ratio = (
+            parse_optional_env("MIN_BOX_ASPECT_RATIO", DEFAULT_MIN_BOX_ASPECT_RATIO),
+            parse_optional_env("MAX_BOX_ASPECT_RATIO", DEFAULT_MAX_BOX_ASPECT_RATIO),
+        );
+        if box_aspect_ratio.0 > box_aspect_ratio.1 {
+            println!("MIN_BOX_ASPECT_RATIO must not exceed MAX_BOX_ASPECT_RATIO");
+            process::exit(1);
+        }
+
+        let results_dir =
+            PathBuf::from(env::var("RESULTS_DIR").unwrap_or(DEFAULT_RESULTS_DIR.to_string()));
+        let bind_address = env::var("BIND_ADDRESS").unwrap_or(DEFAULT_BIND_ADDRESS.to_string());
+        let port = parse_optional_env("PORT", DEFAULT_PORT);
+
+        let grpc_enabled = parse_optional_env("GRPC_ENABLED", false);
+        if grpc_enabled && cfg!(not(feature = "grpc")) {
+            println!("GRPC_ENABLED needs the server built with --features grpc");
+            process::exit(1);
+        }
+        let grpc_port = parse_optional_env("GRPC_PORT", DEFAULT_GRPC_PORT);
+
+        let max_concurrent_inference = parse_env("MAX_CONCURRENT_INFERENCE");
+        if max_concurrent_inference == Some(0) {
+            println!("MAX_CONCURRENT_INFERENCE must be at least 1");
+            process::exit(1);
+        }
+
+        let analytics_refresh = Duration::from_secs(parse_optional_env(
+            "ANALYTICS_REFRESH_SECS",
+            DEFAULT_ANALYTICS_REFRESH_SECS,
+        ));
+        if analytics_refresh.is_zero() {
+            println!("ANALYTICS_REFRESH_SECS must be at least 1");
+            process::exit(1);
+        }
+        let analytics_max_results =
+            parse_optional_env("ANALYTICS_MAX_RESULTS", DEFAULT_ANALYTICS_MAX_RESULTS);
+
+        let audit_log = env::var("AUDIT_LOG_PATH").ok().map(|path| {
+            AuditLog::new(
+                PathBuf::from(path),
+                parse_optional_env("AUDIT_LOG_MAX_MB", DEFAULT_AUDIT_LOG_MAX_MB) * 1024 * 1024,
+                parse_optional_env("AUDIT_LOG_ROTATE_DAILY", false),
+            )
+        });
+        if let Some(AuditLog { max_bytes: 0, .. }) = audit_log {
+            println!("AUDIT_LOG_MAX_MB must be at least 1");
+            process::exit(1);
+        }
+
+        let model_id = env::var("MODEL_ID").unwrap_or_else(|_| model_source.file_name());
+        let model_version = match env::var("MODEL_VERSION") {
+            Ok(model_version) => model_version,
+            Err(_) => model_source.digest().unwrap_or_else(|err| {
+                println!("Unable to hash the model for MODEL_VERSION: {}", err);
+                process::exit(1)
+            }),
+        };
+        let instance_id = env::var("INSTANCE_ID")
+            .ok()
+            .filter(|instance_id| !instance_id.is_empty())
+            .unwrap_or_else(hostname);
+
+        let scale_target_latency = Duration::from_millis(parse_optional_env(
+            "SCALE_TARGET_LATENCY_MS",
+            DEFAULT_SCALE_TARGET_LATENCY_MS,
+        ));
+        if scale_target_latency.is_zero() {
+            println!("SCALE_TARGET_LATENCY_MS must be at least 1");
+            process::exit(1);
+        }
+
+        let pdf_max_pages = parse_optional_env("PDF_MAX_PAGES", DEFAULT_PDF_MAX_PAGES);
+        if pdf_max_pages == 0 {
+            println!("PDF_MAX_PAGES must be at least 1");
+            process::exit(1);
+        }
+
+        let timing_headers = parse_optional_env("TIMING_HEADERS", false);
+        let test_page = parse_optional_env("TEST_PAGE", true);
+        let debug_raw_candidates = parse_optional_env("DEBUG_RAW_CANDIDATES", false);
+
+        let statsd_address = env::var("STATSD_HOST").ok().map(|host| {
+            format!(
+                "{}:{}",
+                host,
+                parse_optional_env("STATSD_PORT", DEFAULT_STATSD_PORT)
+            )
+        });
+        let statsd_prefix =
+            env::var("STATSD_PREFIX").unwrap_or_else(|_| DEFAULT_STATSD_PREFIX.to_string());
+        let statsd_tags = env::var("STATSD_TAGS").ok().filter(|tags| !tags.is_empty());
+
+        // callbacks to the server's own network need to be allowed host by host
+        let callback_allowed_hosts = env::var("CALLBACK_ALLOWED_HOSTS")
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
 
         Config {
-            ultra_model_path: ultra_model_path,
+            model_source,
+            model_id,
+            model_version,
+            instance_id,
             ultra_threads,
+            ultra_inter_threads,
+            results_service,
+            resize_filter,
+            inference_timeout,
+            pretty_json,
+            compress_results,
+            confidence_threshold,
+            report_confidence,
+            rate_limit,
+            rate_limit_keys,
+            idempotency_window,
+            annotation_palette,
+            annotation_font,
+            jpeg_quality,
+            job_ttl,
+            priority_aging,
+            queue_quota,
+            worker_max_restarts,
+            worker_restart_backoff,
+            quarantine,
+            archive,
+            crops,
+            min_image_dimension,
+            decode_limits,
+            queue_full_policy,
+            execution_provider,
+            result_naming,
+            result_write_mode,
+            result_min_confidence,
+            request_timeout,
+            nms_per_class,
+            http_workers,
+            max_connections,
+            anchor_decoding,
+            drain_limit,
+            decode_ahead,
+            tiling,
+            row_tolerance,
+            prescale,
+            admin_api_key,
+            upload_dir,
+            box_aspect_ratio,
+            results_dir,
+            bind_address,
+            port,
+            max_concurrent_inference,
+            nms_mode,
+            input_layout,
+            channel_order,
+            inference_failure_limit,
+            min_accept_confidence,
+            accept_mode,
+            edge_padding,
+            quality_check,
+            thumbnail_max_size,
+            crowd_density_threshold,
+            max_nms_candidates,
+            grpc_enabled,
+            grpc_port,
+            analytics_refresh,
+            analytics_max_results,
+            audit_log,
+            scale_target_latency,
+            pdf_max_pages,
+            timing_headers,
+            test_page,
+            debug_raw_candidates,
+            statsd_address,
+            statsd_prefix,
+            statsd_tags,
+            callback_allowed_hosts,
+        }
+    }
+
+    pub fn effective(&self) -> EffectiveConfig {
+        let resize_filter = match self.resize_filter {
+            FilterType::Nearest => "nearest",
+            FilterType::Triangle => "triangle",
+            FilterType::CatmullRom => "catmullrom",
+            FilterType::Gaussian => "gaussian",
+            FilterType::Lanczos3 => "lanczos3",
+        };
+        let annotation_palette = self
+            .annotation_palette
+            .iter()
+            .map(|(min_confidence, Rgb([r, g, b]))| {
+                (*min_confidence, format!("{:02x}{:02x}{:02x}", r, g, b))
+            })
+            .collect();
+
+        EffectiveConfig {
+            model_source: self.model_source.to_string(),
+            model_id: self.model_id.clone(),
+            model_version: self.model_version.clone(),
+            instance_id: self.instance_id.clone(),
+            ultra_threads: self.ultra_threads,
+            ultra_inter_threads: self.ultra_inter_threads,
+            results_service: self.results_service.name(),
+            resize_filter,
+            inference_timeout_ms: self.inference_timeout.as_millis(),
+            pretty_json: self.pretty_json,
+            compress_results: self.compress_results,
+            confidence_threshold: self.confidence_threshold,
+            report_confidence: self.report_confidence,
+            rate_limit: self.rate_limit,
+            rate_limit_key_count: self.rate_limit_keys.len(),
+            idempotency_window_secs: self.idempotency_window.as_secs(),
+            annotation_palette,
+            annotation_font: self.annotation_font.is_some(),
+            jpeg_quality: self.jpeg_quality,
+            job_ttl_secs: self.job_ttl.as_secs(),
+            priority_aging_ms: self.priority_aging.map(|aging| aging.as_millis()),
+            queue_quota: self.queue_quota,
+            worker_max_restarts: self.worker_max_restarts,
+            worker_restart_backoff_ms: self.worker_restart_backoff.as_millis(),
+            quarantine_dir: self.quarantine.as_ref().map(|q| q.dir.clone()),
+            quarantine_max_files: self.quarantine.as_ref().map(|q| q.max_files),
+            quarantine_ttl_secs: self.quarantine.as_ref().map(|q| q.ttl.as_secs()),
+            archive_dir: self.archive.as_ref().map(|a| a.dir.clone()),
+            archive_max_files: self.archive.as_ref().map(|a| a.max_files),
+            archive_ttl_secs: self.archive.as_ref().map(|a| a.ttl.as_secs()),
+            crops_dir: self.crops.as_ref().map(|c| c.dir.clone()),
+            min_image_dimension: self.min_image_dimension,
+            decode_max_width: self.decode_limits.max_image_width,
+            decode_max_height: self.decode_limits.max_image_height,
+            decode_max_alloc_mb: self
+                .decode_limits
+                .max_alloc
+                .map(|bytes| bytes / 1024 / 1024),
+            queue_full_policy: self.queue_full_policy.name(),
+            execution_provider: self.execution_provider.name(),
+            result_naming: self.result_naming.name(),
+            result_write_mode: self.result_write_mode.name(),
+            result_min_confidence: self.result_min_confidence,
+            request_timeout_ms: self.request_timeout.as_millis(),
+            nms_per_class: self.nms_per_class,
+            http_workers: self.http_workers,
+            max_connections: self.max_connections,
+            anchor_center_variance: self.anchor_decoding.map(|(center, _)| center),
+            anchor_size_variance: self.anchor_decoding.map(|(_, size)| size),
+            worker_batch_size: self.drain_limit,
+            decode_ahead: self.decode_ahead,
+            tile_size: self.tiling.size,
+            tile_overlap: self.tiling.overlap,
+            row_tolerance: self.row_tolerance,
+            prescale: self.prescale,
+            upload_dir: self.upload_dir.clone(),
+            min_box_aspect_ratio: self.box_aspect_ratio.0,
+            max_box_aspect_ratio: self.box_aspect_ratio.1,
+            results_dir: self.results_dir.clone(),
+            bind_address: self.bind_address.clone(),
+            port: self.port,
+            max_concurrent_inference: self.max_concurrent_inference,
+            nms_mode: self.nms_mode.name(),
+            input_layout: self
+                .input_layout
+                .map_or("auto", |input_layout| input_layout.name()),
+            channel_order: self.channel_order.name(),
+            inference_failure_limit: self.inference_failure_limit,
+            min_accept_confidence: self.min_accept_confidence,
+            accept_mode: self.accept_mode.name(),
+            edge_padding: self.edge_padding,
+            min_brightness: self.quality_check.and_then(|check| check.min_brightness),
+            min_contrast: self.quality_check.and_then(|check| check.min_contrast),
+            quality_mode: self.quality_check.map(|check| check.mode.name()),
+            thumbnail_max_size: self.thumbnail_max_size,
+            crowd_density_threshold: self.crowd_density_threshold,
+            max_nms_candidates: self.max_nms_candidates,
+            grpc_enabled: self.grpc_enabled,
+            grpc_port: self.grpc_port,
+            analytics_refresh_secs: self.analytics_refresh.as_secs(),
+            analytics_max_results: self.analytics_max_results,
+            audit_log_path: self.audit_log.as_ref().map(|a| a.path.clone()),
+            audit_log_max_mb: self.audit_log.as_ref().map(|a| a.max_bytes / 1024 / 1024),
+            audit_log_rotate_daily: self.audit_log.as_ref().map(|a| a.rotate_daily),
+            scale_target_latency_ms: self.scale_target_latency.as_millis(),
+            pdf_max_pages: self.pdf_max_pages,
+            timing_headers: self.timing_headers,
+            test_page: self.test_page,
+            debug_raw_candidates: self.debug_raw_candidates,
+            statsd_address: self.statsd_address.clone(),
+            statsd_prefix: self.statsd_prefix.clone(),
+            statsd_tags: self.statsd_tags.clone(),
+            callback_allowed_hosts: self.callback_allowed_hosts.clone(),
+        }
+    }
+}
+
+/// Parse an optional env variable, falling back to `default` when it is not set.
+fn parse_optional_env<T: FromStr>(name: &str, default: T) -> T
+where
+    T::Err: Display,
+{
+    parse_env(name).unwrap_or(default)
+}
+
+fn parse_env<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => Some(value.parse().unwrap_or_else(|err| {
+            println!("Unable to parse {} env variable: {}", name, err);
+            process::exit(1)
+        })),
+        Err(_) => None,
+    }
+}
+
+/// Parse per API key limits formatted as `key=requests_per_second:burst,other_key=...`.
+fn parse_rate_limit_keys(keys: &str) -> Result<HashMap<String, RateLimit>, Error> {
+    let mut rate_limit_keys = HashMap::new();
+    for entry in keys.split(',').filter(|entry| !entry.is_empty()) {
+        let (key, limit) = entry
+            .split_once('=')
+            .ok_or_else(|| Error::Config(format!("missing '=' in {}", entry)))?;
+        let (requests_per_second, burst) = limit
+            .split_once(':')
+            .ok_or_else(|| Error::Config(format!("missing ':' in {}", entry)))?;
+        let limit = RateLimit {
+            requests_per_second: requests_per_second
+                .parse()
+                .map_err(|_| Error::Config(entry.to_string()))?,
+            burst: burst
+                .parse()
+                .map_err(|_| Error::Config(entry.to_string()))?,
+        };
+        rate_limit_keys.insert(key.to_string(), limit);
+    }
+
+    Ok(rate_limit_keys)
+}
+
+/// Name of the host the server runs on, from `HOSTNAME` or `/etc/hostname`.
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Parse a palette formatted as `min_confidence:rrggbb,...`, sorted by descending confidence.
+fn parse_palette(palette: &str) -> Result<Vec<(f32, Rgb<u8>)>, Error> {
+    let mut colors = vec![];
+    for entry in palette.split(',').filter(|entry| !entry.is_empty()) {
+        let (min_confidence, hex) = entry
+            .split_once(':')
+            .ok_or_else(|| Error::Config(format!("missing ':' in {}", entry)))?;
+        let min_confidence: f32 = min_confidence
+            .parse()
+            .map_err(|_| Error::Config(entry.to_string()))?;
+        if !min_confidence.is_finite() {
+            return Err(Error::Config(format!(
+                "confidence must be a finite number in {}",
+                entry
+            )));
+        }
+        let rgb = u32::from_str_radix(hex, 16).map_err(|_| Error::Config(entry.to_string()))?;
+        if hex.len() != 6 {
+            return Err(Error::Config(format!("color must be rrggbb in {}", entry)));
+        }
+        colors.push((
+            min_confidence,
+            Rgb([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]),
+        ));
+    }
+    colors.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    Ok(colors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_is_sorted_by_descending_confidence() {
+        let palette = parse_palette("0.5:ffff00,0.8:00ff00,0:ff0000").unwrap();
+        assert_eq!(
+            palette,
+            vec![
+                (0.8, Rgb([0, 255, 0])),
+                (0.5, Rgb([255, 255, 0])),
+                (0.0, Rgb([255, 0, 0]))
+            ]
+        );
+    }
+
+    #[test]
+    fn palette_rejects_non_finite_confidences() {
+        for palette in ["NaN:ff0000", "0.5:00ff00,inf:ff0000"] {
+            assert!(parse_palette(palette).is_err());
         }
     }
 }
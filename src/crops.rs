@@ -0,0 +1,68 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use image::{DynamicImage, ImageFormat};
+use uuid::Uuid;
+
+use crate::ultra_predictor::BboxPixels;
+
+/// Directory the face crops of completed jobs are kept in, as `{job id}/{detection id}.png`, so
+/// clients can fetch a single face without uploading the image again.
+pub struct CropStore {
+    pub dir: PathBuf,
+    /// Crops of jobs older than this are removed, like the job status.
+    pub ttl: Duration,
+}
+
+impl CropStore {
+    /// Crop every detection out of `image`, the oriented image detection ran on, named by its id.
+    /// Crops of old jobs are removed first.
+    pub fn store(
+        &self,
+        id: &Uuid,
+        image: &DynamicImage,
+        detections: &[(BboxPixels, f32)],
+        detection_ids: &[String],
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        self.clean()?;
+
+        let job_dir = self.dir.join(id.to_string());
+        fs::create_dir_all(&job_dir)?;
+        for (([x1, y1, x2, y2], _), face_id) in detections.iter().zip(detection_ids) {
+            if x2 <= x1 || y2 <= y1 {
+                continue;
+            }
+            let crop = image.crop_imm(*x1, *y1, x2 - x1, y2 - y1);
+            crop.save_with_format(job_dir.join(format!("{}.png", face_id)), ImageFormat::Png)
+                .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    /// Where the crop of `face_id` is, `None` for ids that can not be a detection id.
+    pub fn path(&self, id: &Uuid, face_id: &str) -> Option<PathBuf> {
+        let valid = face_id.len() == 16 && face_id.chars().all(|c| c.is_ascii_hexdigit());
+        valid.then(|| {
+            self.dir
+                .join(id.to_string())
+                .join(format!("{}.png", face_id))
+        })
+    }
+
+    fn clean(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let age = SystemTime::now()
+                .duration_since(entry.metadata()?.modified()?)
+                .unwrap_or_default();
+            if age >= self.ttl {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,714 @@
+use std::{
+    borrow::Cow,
+    io::{BufRead, Cursor, Seek},
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
+
+use image::{
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageError, ImageFormat,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::AcceptMode,
+    encode::thumbnail_data_url,
+    error::Error,
+    ultra_predictor::{
+        merge_detections, BboxPixels, Detections, InferenceTimings, UltraPredictor,
+        ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH,
+    },
+};
+
+/// Clockwise rotation applied to the image before detection.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    pub const ALL: [Rotation; 3] = [Rotation::Rotate90, Rotation::Rotate180, Rotation::Rotate270];
+
+    pub fn from_degrees(degrees: u16) -> Result<Option<Rotation>, String> {
+        match degrees {
+            0 => Ok(None),
+            90 => Ok(Some(Rotation::Rotate90)),
+            180 => Ok(Some(Rotation::Rotate180)),
+            270 => Ok(Some(Rotation::Rotate270)),
+            _ => Err(format!("rotate must be 0, 90, 180 or 270, got {}", degrees)),
+        }
+    }
+
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Rotation::Rotate90 => image.rotate90(),
+            Rotation::Rotate180 => image.rotate180(),
+            Rotation::Rotate270 => image.rotate270(),
+        }
+    }
+
+    /// Map a box detected in the rotated image back to the `width` x `height` image it was
+    /// rotated from.
+    pub fn unrotate_bbox(&self, bbox: BboxPixels, width: u32, height: u32) -> BboxPixels {
+        let [x1, y1, x2, y2] = bbox;
+        match self {
+            Rotation::Rotate90 => [y1, height.saturating_sub(x2), y2, height.saturating_sub(x1)],
+            Rotation::Rotate180 => [
+                width.saturating_sub(x2),
+                height.saturating_sub(y2),
+                width.saturating_sub(x1),
+                height.saturating_sub(y1),
+            ],
+            Rotation::Rotate270 => [width.saturating_sub(y2), x1, width.saturating_sub(y1), x2],
+        }
+    }
+}
+
+/// Region of interest in pixels, detection only runs on this part of the image.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Roi {
+    type Err = String;
+
+    /// Parse `x,y,w,h`, i.e. `?roi=100,50,640,480`.
+    fn from_str(roi: &str) -> Result<Roi, String> {
+        let invalid = || format!("roi must be x,y,w,h, got {}", roi);
+        let values = roi
+            .split(',')
+            .map(|value| value.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| invalid())?;
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Roi {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Criterion picking the single primary face, i.e. for profile photo validation.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Selection {
+    Largest,
+    MostCentral,
+    MostConfident,
+}
+
+impl Selection {
+    pub fn from_name(name: &str) -> Result<Selection, String> {
+        match name {
+            "largest" => Ok(Selection::Largest),
+            "most_central" => Ok(Selection::MostCentral),
+            "most_confident" => Ok(Selection::MostConfident),
+            _ => Err(format!(
+                "select must be largest, most_central or most_confident, got {}",
+                name
+            )),
+        }
+    }
+
+    /// Keep only the best detection, none when there are no detections.
+    pub fn apply(
+        &self,
+        detections: Vec<(BboxPixels, f32)>,
+        width: u32,
+        height: u32,
+    ) -> Vec<(BboxPixels, f32)> {
+        let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+        // higher is better
+        let score = |([x1, y1, x2, y2], confidence): &(BboxPixels, f32)| match self {
+            Selection::Largest => {
+                let (width, height) = (x2.saturating_sub(*x1), y2.saturating_sub(*y1));
+                width as f32 * height as f32
+            }
+            Selection::MostCentral => {
+                let x = (x1 + x2) as f32 / 2.0 - center_x;
+                let y = (y1 + y2) as f32 / 2.0 - center_y;
+                -(x * x + y * y)
+            }
+            Selection::MostConfident => *confidence,
+        };
+        detections
+            .into_iter()
+            .max_by(|a, b| score(a).total_cmp(&score(b)))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Reading order of detections, left to right in rows from top to bottom, i.e. to match the faces
+/// of a class photo to a seating chart.
+#[derive(Clone, Copy)]
+pub struct SpatialSort {
+    /// Boxes whose top edge is at most this many box heights below the top edge of the first box
+    /// of a row are in the same row.
+    pub row_tolerance: f32,
+}
+
+impl SpatialSort {
+    pub fn apply(&self, mut detections: Vec<(BboxPixels, f32)>) -> Vec<(BboxPixels, f32)> {
+        detections.sort_by_key(|([x1, y1, _, _], _)| (*y1, *x1));
+        let mut rows: Vec<Vec<(BboxPixels, f32)>> = Vec::new();
+        for detection in detections {
+            let ([_, y1, _, _], _) = detection;
+            let same_row = match rows.last().and_then(|row| row.first()) {
+                Some(([_, row_y1, _, row_y2], _)) => {
+                    let tolerance = (row_y2.saturating_sub(*row_y1)) as f32 * self.row_tolerance;
+                    (y1 - row_y1) as f32 <= tolerance
+                }
+                None => false,
+            };
+            match (same_row, rows.last_mut()) {
+                (true, Some(row)) => row.push(detection),
+                _ => rows.push(vec![detection]),
+            }
+        }
+        rows.into_iter()
+            .flat_map(|mut row| {
+                row.sort_by_key(|([x1, _, _, _], _)| *x1);
+                row
+            })
+            .collect()
+    }
+}
+
+/// Overlapping tiles detection runs on separately, so faces in wide panoramas are not shrunk below
+/// what the model detects when the whole image is resized to the model input.
+#[derive(Clone, Copy)]
+pub struct Tiling {
+    /// Tile width in pixels, tiles have the aspect ratio of the model input so they are not
+    /// cropped when resized to it.
+    pub size: u32,
+    /// Pixels neighbouring tiles share, a face cut by the border of one tile is whole in the next
+    /// as long as it is smaller than this.
+    pub overlap: u32,
+}
+
+impl Tiling {
+    /// Height of a tile `size` pixels wide.
+    pub fn tile_height(size: u32) -> u32 {
+        (size as usize * ULTRA_INPUT_HEIGHT / ULTRA_INPUT_WIDTH) as u32
+    }
+
+    /// `(x, y, width, height)` of the tiles covering an image of `width` x `height`. Images smaller
+    /// than a tile get smaller tiles, still in the aspect ratio of the model input.
+    pub fn tiles(&self, width: u32, height: u32) -> Vec<(u32, u32, u32, u32)> {
+        let tile_height = Tiling::tile_height(self.size);
+        let scale = f32::min(
+            1.0,
+            f32::min(
+                width as f32 / self.size as f32,
+                height as f32 / tile_height as f32,
+            ),
+        );
+        let scaled = |value: u32| ((value as f32 * scale) as u32).max(1);
+        let (tile_width, tile_height) = (scaled(self.size), scaled(tile_height));
+        let overlap = (self.overlap as f32 * scale) as u32;
+
+        let mut tiles = Vec::new();
+        for y in tile_starts(height, tile_height, overlap) {
+            for x in tile_starts(width, tile_width, overlap) {
+                tiles.push((x, y, tile_width.min(width), tile_height.min(height)));
+            }
+        }
+        tiles
+    }
+}
+
+/// Offsets of tiles of `size` pixels along an axis of `length` pixels, the last tile is moved back
+/// to end at the border instead of sticking out of the image.
+fn tile_starts(length: u32, size: u32, overlap: u32) -> Vec<u32> {
+    let step = size.saturating_sub(overlap).max(1);
+    let mut starts = vec![0];
+    let mut start = 0;
+    while start + size < length {
+        start = (start + step).min(length - size);
+        starts.push(start);
+    }
+    starts
+}
+
+/// Per request options controlling how detection runs on an image.
+#[derive(Clone, Default)]
+pub struct DetectOptions {
+    /// Detected boxes are relative to the rotated image.
+    pub rotation: Option<Rotation>,
+    /// Region of the rotated image to detect in, boxes are still relative to the whole image.
+    pub roi: Option<Roi>,
+    /// Also detect in the image rotated by 90, 180 and 270 degrees, for faces in any orientation.
+    pub multi_orientation: bool,
+    /// Only return the single primary face.
+    pub select: Option<Selection>,
+    /// Detect on overlapping tiles of the region instead of the region resized as a whole.
+    pub tiling: Option<Tiling>,
+    /// Order detections by position instead of by confidence.
+    pub spatial_sort: Option<SpatialSort>,
+    /// Skip inference once this passed, set from the `X-Deadline-Ms` request header.
+    pub deadline: Option<Instant>,
+}
+
+impl DetectOptions {
+    /// Rotate the decoded image into the frame detection runs in and boxes are reported in.
+    pub fn orient(&self, image: DynamicImage) -> DynamicImage {
+        match self.rotation {
+            Some(rotation) => rotation.apply(&image),
+            None => image,
+        }
+    }
+
+    /// The part of the oriented image detection runs on.
+    pub fn region<'a>(&self, image: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        match self.roi {
+            Some(roi) => Cow::Owned(image.crop_imm(roi.x, roi.y, roi.width, roi.height)),
+            None => Cow::Borrowed(image),
+        }
+    }
+
+    /// Check the region of interest lies within an image of `width` x `height` before rotation.
+    pub fn check_roi(&self, width: u32, height: u32) -> Result<(), Error> {
+        let (width, height) = match self.rotation {
+            Some(Rotation::Rotate90) | Some(Rotation::Rotate270) => (height, width),
+            _ => (width, height),
+        };
+        match self.roi {
+            Some(roi)
+                if u64::from(roi.x) + u64::from(roi.width) > u64::from(width)
+                    || u64::from(roi.y) + u64::from(roi.height) > u64::from(height) =>
+            {
+                Err(Error::Validation("roi is outside of the image"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the part of the oriented image detection runs on is at least `min_dimension` pixels
+    /// wide and high, smaller images are upscaled into mostly meaningless model input.
+    pub fn check_size(&self, image: &DynamicImage, min_dimension: u32) -> Result<(), Error> {
+        let (width, height) = match self.roi {
+            Some(roi) => (roi.width, roi.height),
+            None => (image.width(), image.height()),
+        };
+        match width < min_dimension || height < min_dimension {
+            true => Err(Error::Validation("image is too small")),
+            false => Ok(()),
+        }
+    }
+
+    /// Check the client still waits for the result, so no inference is spent on it otherwise.
+    pub fn check_deadline(&self) -> Result<(), Error> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The model a result was detected with, see `MODEL_ID` and `MODEL_VERSION`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub id: String,
+    pub version: String,
+}
+
+/// Version of the `DetectionResult` envelope, bumped whenever its shape changes. The versions
+/// are listed in the README.
+pub static SCHEMA_VERSION: u32 = 8;
+/// `reason` of a result that is not accepted.
+static NOT_ACCEPTED_REASON: &str = "no clear face";
+
+/// Envelope shared by the `/detect` response and the queue result files. Boxes are always in
+/// pixels of the uploaded image at its original resolution, never of the 640x480 model input.
+#[derive(Serialize, Deserialize)]
+pub struct DetectionResult {
+    /// `SCHEMA_VERSION` the result was written with, results from before versioning are version 1.
+    #[serde(default = "first_schema_version")]
+    pub schema_version: u32,
+    /// Size of the uploaded image, after `?rotate` when given.
+    pub image: ImageSize,
+    pub count: usize,
+    pub detections: Vec<(BboxPixels, f32)>,
+    /// Id of each detection in the order of `detections`, see `detection_id`. Empty in results
+    /// before version 2.
+    #[serde(default)]
+    pub detection_ids: Vec<String>,
+    /// Model that produced the detections, `None` in results before version 3.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<ModelMetadata>,
+    /// `INSTANCE_ID` of the server that produced the result, `None` in results before version 5.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    /// Whether a face reaches `MIN_ACCEPT_CONFIDENCE`, `None` when it is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted: Option<bool>,
+    /// Why the result was not accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// `low_light` or `low_contrast` when the image failed the quality pre-check, see
+    /// `MIN_BRIGHTNESS` and `MIN_CONTRAST`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality_warning: Option<String>,
+    /// Small jpeg of the image as a data url, only with `THUMBNAIL_MAX_SIZE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
+    /// Faces counted for the density, the same as `count`, only with `CROWD_DENSITY_THRESHOLD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub face_count: Option<usize>,
+    /// Faces per million pixels of `image`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub faces_per_megapixel: Option<f32>,
+    /// Whether `faces_per_megapixel` reaches `CROWD_DENSITY_THRESHOLD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crowded: Option<bool>,
+    #[serde(skip)]
+    pub timings: InferenceTimings,
+}
+
+impl DetectionResult {
+    /// Accept the result when its most confident face reaches `min_confidence`. `AcceptMode::Reject`
+    /// drops the detections of a result that is not accepted.
+    pub fn accept(&mut self, min_confidence: f32, mode: AcceptMode) {
+        let accepted = self
+            .detections
+            .iter()
+            .any(|(_, confidence)| *confidence >= min_confidence);
+        self.accepted = Some(accepted);
+        if accepted {
+            return;
+        }
+        self.reason = Some(NOT_ACCEPTED_REASON.to_string());
+        if mode == AcceptMode::Reject {
+            self.detections.clear();
+            self.detection_ids.clear();
+            self.count = 0;
+        }
+    }
+
+    /// Add the face density of the result, crowded from `crowd_threshold` faces per megapixel.
+    /// Measured after `accept`, so rejected detections do not count.
+    pub fn measure_density(&mut self, crowd_threshold: f32) {
+        let megapixels = self.image.width as f32 * self.image.height as f32 / 1_000_000.0;
+        let faces_per_megapixel = match megapixels > 0.0 {
+            true => self.count as f32 / megapixels,
+            false => 0.0,
+        };
+        self.face_count = Some(self.count);
+        self.faces_per_megapixel = Some(faces_per_megapixel);
+        self.crowded = Some(faces_per_megapixel >= crowd_threshold);
+    }
+
+    /// Key the detection ids to a job, so boxes of different jobs never share an id.
+    pub fn assign_ids(&mut self, key: &str) {
+        self.detection_ids = detection_ids(key, &self.detections);
+    }
+}
+
+/// Stable id of a detection, the first 16 hex digits of the sha256 of `key` and its box. The same
+/// box of the same job always gets the same id. Synchronous results use an empty key, their ids
+/// only depend on the box.
+pub fn detection_id(key: &str, bbox: &BboxPixels) -> String {
+    let [x1, y1, x2, y2] = bbox;
+    let hash = Sha256::digest(format!("{}:{},{},{},{}", key, x1, y1, x2, y2));
+    format!("{:x}", hash)[..16].to_string()
+}
+
+fn detection_ids(key: &str, detections: &[(BboxPixels, f32)]) -> Vec<String> {
+    detections
+        .iter()
+        .map(|(bbox, _)| detection_id(key, bbox))
+        .collect()
+}
+
+fn first_schema_version() -> u32 {
+    1
+}
+
+/// Open and decode an uploaded image, failing fast when it exceeds the decode `limits`.
+pub fn load_image(
+    image_location: &Path,
+    format: ImageFormat,
+    limits: Limits,
+) -> Result<DynamicImage, Error> {
+    let image_buf =
+        Reader::open(image_location).map_err(|_| Error::Decode("unable to open image"))?;
+    decode(image_buf, format, limits)
+}
+
+/// `load_image` for an upload held in memory.
+pub fn decode_image(
+    image_bytes: &[u8],
+    format: ImageFormat,
+    limits: Limits,
+) -> Result<DynamicImage, Error> {
+    decode(Reader::new(Cursor::new(image_bytes)), format, limits)
+}
+
+fn decode<R: BufRead + Seek>(
+    mut image_buf: Reader<R>,
+    format: ImageFormat,
+    limits: Limits,
+) -> Result<DynamicImage, Error> {
+    image_buf.set_format(format);
+    image_buf.limits(limits);
+    image_buf.decode().map_err(|err| match err {
+        ImageError::Limits(_) => Error::Decode("image exceeds decode limits"),
+        _ => Error::Decode("corrupt or truncated image"),
+    })
+}
+
+/// Detect faces in an image already oriented with `DetectOptions::orient`. The region of interest
+/// must have been checked with `DetectOptions::check_roi`.
+pub fn detect_faces(
+    ultra_predictor: &UltraPredictor,
+    image: &DynamicImage,
+    options: &DetectOptions,
+    resize_filter: FilterType,
+) -> Result<DetectionResult, Error> {
+    let thresholds = [ultra_predictor.confidence_threshold];
+    let mut results =
+        detect_faces_at_thresholds(ultra_predictor, image, options, resize_filter, &thresholds)?;
+    Ok(results.remove(0))
+}
+
+/// `detect_faces` once per confidence threshold, sharing the inference between them. The
+/// results are in the order of `thresholds` and all carry the timings of the whole detection.
+pub fn detect_faces_at_thresholds(
+    ultra_predictor: &UltraPredictor,
+    image: &DynamicImage,
+    options: &DetectOptions,
+    resize_filter: FilterType,
+    thresholds: &[f32],
+) -> Result<Vec<DetectionResult>, Error> {
+    let region = options.region(image);
+    let quality_warning = match &ultra_predictor.quality_check {
+        Some(quality_check) => quality_check.check(&region)?,
+        None => None,
+    };
+    let thumbnail = ultra_predictor.thumbnail_max_size.and_then(|max_size| {
+        thumbnail_data_url(image, max_size)
+            .map_err(|err| println!("unable to encode thumbnail; {}", err))
+            .ok()
+    });
+    let (detections, timings) = match options.tiling {
+        Some(tiling) => detect_tiled(
+            ultra_predictor,
+            &region,
+            tiling,
+            options.multi_orientation,
+            resize_filter,
+            thresholds,
+        )?,
+        None => detect_whole(
+            ultra_predictor,
+            &region,
+            options.multi_orientation,
+            resize_filter,
+            thresholds,
+        )?,
+    };
+
+    let results = detections
+        .into_iter()
+        .map(|mut detections| {
+            // map boxes from the region back to the whole image
+            if let Some(roi) = options.roi {
+                for ([x1, y1, x2, y2], _) in detections.iter_mut() {
+                    *x1 += roi.x;
+                    *y1 += roi.y;
+                    *x2 += roi.x;
+                    *y2 += roi.y;
+                }
+            }
+
+            if let Some(padding) = ultra_predictor.edge_padding {
+                pad_edge_boxes(&mut detections, image.width(), image.height(), padding);
+            }
+
+            if let Some(selection) = options.select {
+                detections = selection.apply(detections, image.width(), image.height());
+            }
+            if let Some(spatial_sort) = options.spatial_sort {
+                detections = spatial_sort.apply(detections);
+            }
+
+            let mut result = DetectionResult {
+                schema_version: SCHEMA_VERSION,
+                image: ImageSize {
+                    width: image.width(),
+                    height: image.height(),
+                },
+                count: detections.len(),
+                detection_ids: detection_ids("", &detections),
+                detections,
+                model: Some(ultra_predictor.model.clone()),
+                instance_id: Some(ultra_predictor.instance_id.clone()),
+                accepted: None,
+                reason: None,
+                quality_warning: quality_warning.map(str::to_string),
+                thumbnail: thumbnail.clone(),
+                face_count: None,
+                faces_per_megapixel: None,
+                crowded: None,
+                timings,
+            };
+            if let Some(min_confidence) = ultra_predictor.min_accept_confidence {
+                result.accept(min_confidence, ultra_predictor.accept_mode);
+            }
+            if let Some(crowd_threshold) = ultra_predictor.crowd_density_threshold {
+                result.measure_density(crowd_threshold);
+            }
+            result
+        })
+        .collect();
+    Ok(results)
+}
+
+fn detect_whole(
+    ultra_predictor: &UltraPredictor,
+    image: &DynamicImage,
+    multi_orientation: bool,
+    resize_filter: FilterType,
+    thresholds: &[f32],
+) -> Result<(Vec<Detections>, InferenceTimings), Error> {
+    match multi_orientation {
+        true => detect_all_orientations(ultra_predictor, image, resize_filter, thresholds),
+        false => ultra_predictor.run_at_thresholds(image, resize_filter, thresholds),
+    }
+}
+
+/// Detect in each tile of `tiling`, merging the boxes mapped back to the image with
+/// non-maximum-suppression so a face found in several overlapping tiles is reported once.
+fn detect_tiled(
+    ultra_predictor: &UltraPredictor,
+    image: &DynamicImage,
+    tiling: Tiling,
+    multi_orientation: bool,
+    resize_filter: FilterType,
+    thresholds: &[f32],
+) -> Result<(Vec<Detections>, InferenceTimings), Error> {
+    let mut detections = vec![Vec::new(); thresholds.len()];
+    let mut timings = InferenceTimings::default();
+    for (x, y, width, height) in tiling.tiles(image.width(), image.height()) {
+        let tile = image.crop_imm(x, y, width, height);
+        let (tile_detections, tile_timings) = detect_whole(
+            ultra_predictor,
+            &tile,
+            multi_orientation,
+            resize_filter,
+            thresholds,
+        )?;
+        for (detections, tile_detections) in detections.iter_mut().zip(tile_detections) {
+            detections.extend(
+                tile_detections
+                    .into_iter()
+                    .map(|([x1, y1, x2, y2], confidence)| {
+                        ([x1 + x, y1 + y, x2 + x, y2 + y], confidence)
+                    }),
+            );
+        }
+        timings += tile_timings;
+    }
+
+    Ok((
+        detections.into_iter().map(merge_detections).collect(),
+        timings,
+    ))
+}
+
+/// Grow the boxes touching the border of an image of `width` x `height` by `padding` times their
+/// size on every side, clamped to the image. The model tends to cut faces at the border short,
+/// aligning a crop of a partially visible face needs the whole visible part.
+fn pad_edge_boxes(detections: &mut [(BboxPixels, f32)], width: u32, height: u32, padding: f32) {
+    for ([x1, y1, x2, y2], _) in detections.iter_mut() {
+        // boxes are clipped to the image, a box on its border is cut off by it
+        if *x1 > 0 && *y1 > 0 && *x2 < width && *y2 < height {
+            continue;
+        }
+        let pad_x = (x2.saturating_sub(*x1) as f32 * padding).round() as u32;
+        let pad_y = (y2.saturating_sub(*y1) as f32 * padding).round() as u32;
+        *x1 = x1.saturating_sub(pad_x);
+        *y1 = y1.saturating_sub(pad_y);
+        *x2 = x2.saturating_add(pad_x).min(width);
+        *y2 = y2.saturating_add(pad_y).min(height);
+    }
+}
+
+/// Detect in the image rotated by 0, 90, 180 and 270 degrees, merging the boxes mapped back to
+/// the unrotated image with non-maximum-suppression so the most confident box of each face wins.
+fn detect_all_orientations(
+    ultra_predictor: &UltraPredictor,
+    image: &DynamicImage,
+    resize_filter: FilterType,
+    thresholds: &[f32],
+) -> Result<(Vec<Detections>, InferenceTimings), Error> {
+    let (mut detections, mut timings) =
+        ultra_predictor.run_at_thresholds(image, resize_filter, thresholds)?;
+    for rotation in Rotation::ALL {
+        let (rotated_detections, rotated_timings) =
+            ultra_predictor.run_at_thresholds(&rotation.apply(image), resize_filter, thresholds)?;
+        for (detections, rotated_detections) in detections.iter_mut().zip(rotated_detections) {
+            detections.extend(rotated_detections.into_iter().map(|(bbox, confidence)| {
+                let bbox = rotation.unrotate_bbox(bbox, image.width(), image.height());
+                (bbox, confidence)
+            }));
+        }
+        timings += rotated_timings;
+    }
+
+    Ok((
+        detections.into_iter().map(merge_detections).collect(),
+        timings,
+    ))
+}
+
+/// Fast path of `detect_faces` returning whether any face is found, see `UltraPredictor::has_face`.
+pub fn detect_any_face(
+    ultra_predictor: &UltraPredictor,
+    image: &DynamicImage,
+    options: &DetectOptions,
+    resize_filter: FilterType,
+) -> Result<bool, Error> {
+    let region = options.region(image);
+    let tiles = match options.tiling {
+        Some(tiling) => tiling.tiles(region.width(), region.height()),
+        None => vec![(0, 0, region.width(), region.height())],
+    };
+    for (x, y, width, height) in tiles {
+        let tile = match options.tiling {
+            Some(_) => Cow::Owned(region.crop_imm(x, y, width, height)),
+            None => Cow::Borrowed(region.as_ref()),
+        };
+        if ultra_predictor.has_face(&tile, resize_filter)? {
+            return Ok(true);
+        }
+        if options.multi_orientation {
+            for rotation in Rotation::ALL {
+                if ultra_predictor.has_face(&rotation.apply(&tile), resize_filter)? {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,118 @@
+use std::io::Cursor;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+#[cfg(feature = "webp")]
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+#[cfg(feature = "avif")]
+use image::{codecs::avif::AvifEncoder, ImageEncoder};
+use image::{DynamicImage, ImageOutputFormat, ImageResult};
+
+/// Jpeg quality of the thumbnails embedded in results, they are only meant for previews.
+static THUMBNAIL_JPEG_QUALITY: u8 = 70;
+
+/// Speed (0-10) of the avif encoder, the `cavif` default trading size against encoding time.
+#[cfg(feature = "avif")]
+static AVIF_SPEED: u8 = 4;
+
+/// Encoding of images returned by the image endpoints. WebP and AVIF need the `webp` and `avif`
+/// cargo features, without them those formats fall back to png.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    #[cfg(feature = "webp")]
+    WebP,
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "png" => Some(OutputFormat::Png),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            #[cfg(feature = "webp")]
+            "webp" => Some(OutputFormat::WebP),
+            #[cfg(feature = "avif")]
+            "avif" => Some(OutputFormat::Avif),
+            // fall back to png when the encoder is not compiled in
+            #[cfg(not(feature = "webp"))]
+            "webp" => Some(OutputFormat::Png),
+            #[cfg(not(feature = "avif"))]
+            "avif" => Some(OutputFormat::Png),
+            _ => None,
+        }
+    }
+
+    /// First media type of an `Accept` header this server can encode, i.e. `image/webp` for
+    /// `image/avif,image/webp,*/*` when only the `webp` feature is enabled.
+    pub fn from_accept(accept: &str) -> Option<OutputFormat> {
+        accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+            .find_map(|media_type| match media_type {
+                "image/png" => Some(OutputFormat::Png),
+                "image/jpeg" => Some(OutputFormat::Jpeg),
+                #[cfg(feature = "webp")]
+                "image/webp" => Some(OutputFormat::WebP),
+                #[cfg(feature = "avif")]
+                "image/avif" => Some(OutputFormat::Avif),
+                _ => None,
+            })
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            #[cfg(feature = "webp")]
+            OutputFormat::WebP => "image/webp",
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+}
+
+/// Encode `image`, `quality` (1-100) is used by the lossy formats jpeg, webp and avif.
+pub fn encode_image(
+    image: &DynamicImage,
+    format: OutputFormat,
+    quality: u8,
+) -> ImageResult<Vec<u8>> {
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Png => image.write_to(&mut encoded, ImageOutputFormat::Png)?,
+        OutputFormat::Jpeg => image.write_to(&mut encoded, ImageOutputFormat::Jpeg(quality))?,
+        #[cfg(feature = "webp")]
+        OutputFormat::WebP => {
+            // the webp encoder only takes 8 bit rgb
+            let image = image.to_rgb8();
+            WebPEncoder::new_with_quality(&mut encoded, WebPQuality::lossy(quality)).encode(
+                &image,
+                image.width(),
+                image.height(),
+                image::ColorType::Rgb8,
+            )?
+        }
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => {
+            let image = image.to_rgb8();
+            AvifEncoder::new_with_speed_quality(&mut encoded, AVIF_SPEED, quality).write_image(
+                &image,
+                image.width(),
+                image.height(),
+                image::ColorType::Rgb8,
+            )?
+        }
+    };
+    Ok(encoded.into_inner())
+}
+
+/// Jpeg thumbnail of `image` of at most `max_size` pixels per side as a `data:image/jpeg;base64,`
+/// url, ready to be used as the `src` of an `img`.
+pub fn thumbnail_data_url(image: &DynamicImage, max_size: u32) -> ImageResult<String> {
+    // jpeg has no alpha channel
+    let thumbnail = DynamicImage::ImageRgb8(image.thumbnail(max_size, max_size).to_rgb8());
+    let jpeg = encode_image(&thumbnail, OutputFormat::Jpeg, THUMBNAIL_JPEG_QUALITY)?;
+    Ok(format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg)))
+}
@@ -0,0 +1,30 @@
+use std::io;
+
+use ort::OrtError;
+use thiserror::Error;
+
+/// Failures of the library, the `Display` of decode and validation errors is meant for clients.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// An invalid configuration value.
+    #[error("{0}")]
+    Config(String),
+    /// The client's deadline passed before inference started.
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+    /// The image could not be opened or decoded, i.e. `corrupt or truncated image`.
+    #[error("{0}")]
+    Decode(&'static str),
+    #[error("inference failed; {0}")]
+    Inference(#[from] OrtError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The model outputs are not shaped like the ultra-light face detector's.
+    #[error("unexpected model output; {0}")]
+    ModelOutput(&'static str),
+    /// The image or request options are unusable for detection, i.e. a too small image.
+    #[error("{0}")]
+    Validation(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
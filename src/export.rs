@@ -0,0 +1,172 @@
+use std::{
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use tokio::sync::mpsc::Sender;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    detection::DetectionResult,
+    result_file::{read_result, result_path},
+    voc::voc_annotation,
+};
+
+/// Bundle the `{name}.json` result files in `results_dir` into a zip archive written to `writer`.
+/// Fails with `io::ErrorKind::NotFound` when one of the results does not exist (yet). Compressed
+/// results are added decompressed.
+///
+/// With `voc` the detection results are added as `{name}.xml` Pascal VOC annotations instead,
+/// error results of failed jobs stay JSON.
+pub fn zip_results<W: Write + Seek>(
+    writer: W,
+    results_dir: &Path,
+    names: &[String],
+    voc: bool,
+) -> io::Result<W> {
+    let mut archive = ZipWriter::new(writer);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for name in names {
+        let file_name = name.to_string() + ".json";
+        let result = read_result(&result_path(results_dir, name))?;
+        let detection = match voc {
+            true => serde_json::from_slice::<DetectionResult>(&result).ok(),
+            false => None,
+        };
+        match detection {
+            Some(detection) => {
+                archive.start_file(name.to_string() + ".xml", options)?;
+                archive.write_all(voc_annotation(&detection, name).as_bytes())?;
+            }
+            _ => {
+                archive.start_file(file_name, options)?;
+                archive.write_all(&result)?;
+            }
+        }
+    }
+    Ok(archive.finish()?)
+}
+
+/// Sends a zip archive to a channel while it is written, holding at most one entry in memory.
+///
+/// The zip writer only seeks back into the entry it is writing, to fill in its checksum and
+/// sizes, and then seeks to the end again. Everything before that end is final and sent.
+pub struct ChannelWriter {
+    sender: Sender<io::Result<Vec<u8>>>,
+    /// Bytes not sent yet, starting at offset `sent` of the archive.
+    buffer: Vec<u8>,
+    sent: u64,
+    position: u64,
+}
+
+impl ChannelWriter {
+    pub fn new(sender: Sender<io::Result<Vec<u8>>>) -> ChannelWriter {
+        ChannelWriter {
+            sender,
+            buffer: Vec::new(),
+            sent: 0,
+            position: 0,
+        }
+    }
+
+    /// Send what is left, the archive is complete.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn end(&self) -> u64 {
+        self.sent + self.buffer.len() as u64
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::take(&mut self.buffer);
+        self.sent += chunk.len() as u64;
+        // the receiver is dropped when the client went away
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "export download was aborted"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let start = (self.position - self.sent) as usize;
+        let overlap = data.len().min(self.buffer.len().saturating_sub(start));
+        self.buffer[start..start + overlap].copy_from_slice(&data[..overlap]);
+        self.buffer.extend_from_slice(&data[overlap..]);
+        self.position += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ChannelWriter {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(position) => position,
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset).unwrap_or(0),
+            SeekFrom::End(offset) => self.end().checked_add_signed(offset).unwrap_or(0),
+        };
+        if position < self.sent || position > self.end() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "seek outside of the zip entry being written",
+            ));
+        }
+        let returned_to_end = position == self.end() && self.position < self.end();
+        self.position = position;
+        if returned_to_end {
+            self.send()?;
+        }
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn streamed_archive_matches_buffered_archive() {
+        let results_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&results_dir).unwrap();
+        let names: Vec<String> = (0..3).map(|index| format!("result-{}", index)).collect();
+        for name in &names {
+            let result = format!("{{\"err\": \"{}\"}}", name.repeat(1000));
+            fs::write(results_dir.join(name.to_string() + ".json"), result).unwrap();
+        }
+
+        let buffered = zip_results(Cursor::new(Vec::new()), &results_dir, &names, false)
+            .unwrap()
+            .into_inner();
+        let (sender, mut receiver) = mpsc::channel(16);
+        zip_results(ChannelWriter::new(sender), &results_dir, &names, false)
+            .and_then(ChannelWriter::finish)
+            .unwrap();
+        let mut chunks = 0;
+        let mut streamed = Vec::new();
+        while let Ok(chunk) = receiver.try_recv() {
+            streamed.extend(chunk.unwrap());
+            chunks += 1;
+        }
+        fs::remove_dir_all(&results_dir).unwrap();
+
+        // one chunk per entry and the central directory
+        assert_eq!(chunks, names.len() + 1);
+        assert_eq!(streamed, buffered);
+        let archive = ZipArchive::new(Cursor::new(streamed)).unwrap();
+        assert_eq!(archive.len(), names.len());
+    }
+}
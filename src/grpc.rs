@@ -0,0 +1,125 @@
+use std::{net::SocketAddr, pin::Pin, sync::Arc};
+
+use image::ImageFormat;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use crate::{
+    config::Config,
+    detection::{decode_image, detect_faces, DetectOptions},
+    error::Error,
+    proto::DetectionResult,
+    ultra_predictor::UltraPredictor,
+};
+
+pub mod pb {
+    tonic::include_proto!("face_detection");
+}
+
+use pb::{
+    face_detection_server::{FaceDetection, FaceDetectionServer},
+    DetectRequest,
+};
+
+/// Results of a `DetectStream` buffered before the stream stops reading requests.
+static STREAM_BUFFER: usize = 16;
+
+/// gRPC `FaceDetection` service, sharing the `UltraPredictor` of the HTTP server.
+#[derive(Clone)]
+pub struct DetectionService {
+    ultra_predictor: Arc<UltraPredictor>,
+    config: Arc<Config>,
+}
+
+impl DetectionService {
+    /// Decode and detect on the blocking pool, like the synchronous HTTP endpoints.
+    async fn detect_image(&self, image: Vec<u8>) -> Result<DetectionResult, Status> {
+        let (ultra_predictor, config) = (self.ultra_predictor.clone(), self.config.clone());
+        let detection = tokio::task::spawn_blocking(move || {
+            let format = match image::guess_format(&image) {
+                Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg)) => format,
+                _ => return Err(Error::Validation("image must be a png or jpeg")),
+            };
+            let image = decode_image(&image, format, config.decode_limits.clone())?;
+            let options = DetectOptions::default();
+            options.check_size(&image, config.min_image_dimension)?;
+            detect_faces(&ultra_predictor, &image, &options, config.resize_filter)
+        })
+        .await;
+
+        match detection {
+            Ok(Ok(result)) => Ok(DetectionResult::from(&result)),
+            Ok(Err(err @ (Error::Decode(_) | Error::Validation(_)))) => {
+                Err(Status::invalid_argument(err.to_string()))
+            }
+            Ok(Err(err)) => {
+                println!("{}", err);
+                Err(Status::internal("detection failed"))
+            }
+            Err(_) => Err(Status::internal("detection failed")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl FaceDetection for DetectionService {
+    async fn detect(
+        &self,
+        request: Request<DetectRequest>,
+    ) -> Result<Response<DetectionResult>, Status> {
+        let result = self.detect_image(request.into_inner().image).await?;
+        Ok(Response::new(result))
+    }
+
+    type DetectStreamStream =
+        Pin<Box<dyn Stream<Item = Result<DetectionResult, Status>> + Send + 'static>>;
+
+    async fn detect_stream(
+        &self,
+        request: Request<Streaming<DetectRequest>>,
+    ) -> Result<Response<Self::DetectStreamStream>, Status> {
+        let mut requests = request.into_inner();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let (result, last) = match requests.message().await {
+                    Ok(Some(request)) => (service.detect_image(request.image).await, false),
+                    Ok(None) => break,
+                    Err(status) => (Err(status), true),
+                };
+                // stop when the client went away or the request stream broke
+                if sender.send(result).await.is_err() || last {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+/// Serve the gRPC interface on `GRPC_PORT` of the HTTP bind address until the process exits.
+pub async fn serve_grpc(
+    ultra_predictor: Arc<UltraPredictor>,
+    config: Arc<Config>,
+) -> Result<(), tonic::transport::Error> {
+    // BIND_ADDRESS may be a host name, which only the HTTP server resolves
+    let address = SocketAddr::new(
+        config
+            .bind_address
+            .parse()
+            .unwrap_or_else(|_| [127, 0, 0, 1].into()),
+        config.grpc_port,
+    );
+    let service = DetectionService {
+        ultra_predictor,
+        config,
+    };
+    println!("serving gRPC on {}", address);
+    Server::builder()
+        .add_service(FaceDetectionServer::new(service))
+        .serve(address)
+        .await
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+static MAX_KEYS: usize = 10000;
+
+/// Job id and result name created for a key.
+type Job = (Uuid, String);
+
+/// Bounded map of client supplied idempotency keys to the job created for them.
+pub struct IdempotencyKeys {
+    window: Duration,
+    /// The job id and result name of each key, `None` while the first request with the key is
+    /// still enqueuing.
+    keys: Mutex<HashMap<String, (Option<Job>, Instant)>>,
+}
+
+/// What a request finds for its idempotency key.
+pub enum KeyState<'a> {
+    /// First request with the key, it holds the key until its job is queued.
+    New(Reservation<'a>),
+    /// Retry of a request that created this job and result name.
+    Existing(Uuid, String),
+    /// Another request with the key is still being enqueued.
+    InProgress,
+}
+
+/// A key held by the request creating its job. Dropping it without `complete`, i.e. when the
+/// upload was rejected, frees the key for a retry.
+pub struct Reservation<'a> {
+    keys: &'a IdempotencyKeys,
+    key: String,
+    completed: bool,
+}
+
+impl IdempotencyKeys {
+    pub fn new(window: Duration) -> IdempotencyKeys {
+        IdempotencyKeys {
+            window,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request with `key` was seen within the window.
+    pub fn contains(&self, key: &str) -> bool {
+        let keys = self.keys.lock().unwrap();
+        keys.get(key)
+            .is_some_and(|(_, added_time)| added_time.elapsed() < self.window)
+    }
+
+    /// Look up `key` and reserve it if it is new, under one lock so concurrent requests with the
+    /// same key can not both create a job.
+    pub fn reserve(&self, key: String) -> KeyState<'_> {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get(&key) {
+            Some((Some((id, result_name)), added_time)) if added_time.elapsed() < self.window => {
+                return KeyState::Existing(*id, result_name.clone())
+            }
+            Some((None, added_time)) if added_time.elapsed() < self.window => {
+                return KeyState::InProgress
+            }
+            _ => {}
+        }
+
+        if keys.len() >= MAX_KEYS {
+            let window = self.window;
+            keys.retain(|_, (_, added_time)| added_time.elapsed() < window);
+        }
+        if keys.len() >= MAX_KEYS {
+            let oldest = keys
+                .iter()
+                .min_by_key(|(_, (_, added_time))| *added_time)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                keys.remove(&oldest);
+            }
+        }
+        keys.insert(key.clone(), (None, Instant::now()));
+        KeyState::New(Reservation {
+            keys: self,
+            key,
+            completed: false,
+        })
+    }
+}
+
+impl Reservation<'_> {
+    /// Record the job and result name created for the key, retries return them from now on.
+    pub fn complete(mut self, id: Uuid, result_name: String) {
+        let mut keys = self.keys.keys.lock().unwrap();
+        keys.insert(self.key.clone(), (Some((id, result_name)), Instant::now()));
+        self.completed = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let mut keys = self.keys.keys.lock().unwrap();
+        // the key may have expired and been reserved again by another request
+        if let Some((None, _)) = keys.get(&self.key) {
+            keys.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread};
+
+    use super::*;
+
+    #[test]
+    fn concurrent_requests_with_one_key_create_one_job() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        let barrier = Barrier::new(2);
+        let id = Uuid::new_v4();
+        let reserved: Vec<bool> = thread::scope(|scope| {
+            let requests: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        match keys.reserve("api-key:retry".to_string()) {
+                            KeyState::New(reservation) => {
+                                // the other request checks the key while this one enqueues
+                                barrier.wait();
+                                reservation.complete(id, id.to_string());
+                                true
+                            }
+                            _ => {
+                                barrier.wait();
+                                false
+                            }
+                        }
+                    })
+                })
+                .collect();
+            requests
+                .into_iter()
+                .map(|request| request.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(reserved.iter().filter(|reserved| **reserved).count(), 1);
+        assert!(matches!(
+            keys.reserve("api-key:retry".to_string()),
+            KeyState::Existing(existing, _) if existing == id
+        ));
+    }
+
+    #[test]
+    fn rejected_request_frees_its_key() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        let reservation = keys.reserve("key".to_string());
+        assert!(matches!(
+            keys.reserve("key".to_string()),
+            KeyState::InProgress
+        ));
+        drop(reservation);
+        assert!(matches!(keys.reserve("key".to_string()), KeyState::New(_)));
+    }
+}
@@ -1,48 +1,206 @@
 use std::{
+    cmp::Reverse,
+    collections::VecDeque,
     path::PathBuf,
-    sync::{Arc, Mutex},
-    time::SystemTime,
+    sync::Mutex,
+    time::{Duration, SystemTime},
 };
 
 use image::ImageFormat;
+use url::Url;
 use uuid::Uuid;
 
+use crate::{detection::DetectOptions, queue_quota::QuotaPermit};
+
 static QUEUE_SIZE: usize = 10000;
 
+/// Order queued items are processed in, items of the same priority stay in upload order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn from_name(name: &str) -> Result<Priority, String> {
+        match name {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(format!(
+                "priority must be high, normal or low, got {}",
+                name
+            )),
+        }
+    }
+}
+
 pub struct QueueItem {
     pub id: Uuid,
     pub image_location: PathBuf,
     pub format: ImageFormat,
+    pub options: DetectOptions,
+    /// Results are written to `{result_name}.json`.
+    pub result_name: String,
     pub added_time: SystemTime,
+    pub priority: Priority,
+    /// Url the result is POSTed to once the job is done or failed.
+    pub callback_url: Option<Url>,
+    /// Slot of the client's `QUEUE_QUOTA`, freed when the item is dropped after processing.
+    pub quota_permit: Option<QuotaPermit>,
+}
+
+/// How a job is queued and where its result goes, next to the `DetectOptions` of its detection.
+pub struct QueueOptions {
+    /// Named after the job id when `None`.
+    pub result_name: Option<String>,
+    pub priority: Priority,
+    pub callback_url: Option<Url>,
+    pub quota_permit: Option<QuotaPermit>,
 }
 
 pub struct ImageQueue {
-    pub queue: Arc<Mutex<Vec<QueueItem>>>,
+    /// Items of each priority in upload order, indexed by `Priority as usize`. Aging only
+    /// changes which queue the next item is taken from, never where an item is stored.
+    queues: Mutex<[VecDeque<QueueItem>; 3]>,
+    /// Items are raised a priority level for every `priority_aging` they waited, so a steady
+    /// stream of higher priority uploads does not starve them. `None` never raises them.
+    pub priority_aging: Option<Duration>,
 }
 
 impl ImageQueue {
-    pub fn new() -> ImageQueue {
-        let queue = Arc::new(Mutex::new(Vec::with_capacity(QUEUE_SIZE)));
-        ImageQueue { queue }
+    pub fn new(priority_aging: Option<Duration>) -> ImageQueue {
+        ImageQueue {
+            queues: Mutex::new(Default::default()),
+            priority_aging,
+        }
+    }
+
+    /// Take the item of the highest priority that has been waiting longest, if any. The worker
+    /// takes one item at a time, so an upload of a higher priority is next as soon as it is queued.
+    pub fn pop_next(&self) -> Option<QueueItem> {
+        let mut queues = self.queues.lock().unwrap();
+        let now = SystemTime::now();
+        // the oldest item of a priority is also its most aged one, so the next item is always at
+        // the front of one of the queues
+        let next = queues
+            .iter()
+            .enumerate()
+            .filter_map(|(index, queue)| Some((index, queue.front()?)))
+            .max_by_key(|(_, item)| {
+                let priority = match self.priority_aging {
+                    Some(aging) => aged_priority(item, aging, now),
+                    None => item.priority as usize,
+                };
+                (priority, Reverse(item.added_time))
+            })
+            .map(|(index, _)| index)?;
+        queues[next].pop_front()
     }
 
-    pub fn drain(&self) -> Vec<QueueItem> {
-        let queue_items: Vec<QueueItem> = { self.queue.lock().unwrap().drain(..).collect() };
-        queue_items
+    /// Remove the item of the lowest priority that has been waiting longest, if any.
+    pub fn pop_oldest(&self) -> Option<QueueItem> {
+        let mut queues = self.queues.lock().unwrap();
+        queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.lock().unwrap().iter().map(VecDeque::len).sum()
     }
 
     pub fn is_full(&self) -> bool {
-        self.queue.lock().unwrap().len() > QUEUE_SIZE
+        self.len() > QUEUE_SIZE
     }
 
-    pub fn push(&self, image_location: PathBuf, format: ImageFormat) -> Uuid {
+    pub fn push(
+        &self,
+        image_location: PathBuf,
+        format: ImageFormat,
+        options: DetectOptions,
+        queue_options: QueueOptions,
+    ) -> Uuid {
         let id = Uuid::new_v4();
-        self.queue.lock().unwrap().push(QueueItem {
+        let priority = queue_options.priority;
+        self.queues.lock().unwrap()[priority as usize].push_back(QueueItem {
             id,
             image_location,
             format,
+            options,
+            result_name: queue_options.result_name.unwrap_or_else(|| id.to_string()),
             added_time: SystemTime::now(),
+            priority,
+            callback_url: queue_options.callback_url,
+            quota_permit: queue_options.quota_permit,
         });
         return id;
     }
 }
+
+/// Priority level of `item` raised by one for every `aging` it waited, at most to `High`.
+fn aged_priority(item: &QueueItem, aging: Duration, now: SystemTime) -> usize {
+    let waited = now.duration_since(item.added_time).unwrap_or_default();
+    let raised = waited.as_millis() / aging.as_millis().max(1);
+    (item.priority as usize)
+        .saturating_add(raised as usize)
+        .min(Priority::High as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{iter, thread};
+
+    use super::*;
+
+    fn push(queue: &ImageQueue, name: &str, priority: Priority) {
+        queue.push(
+            PathBuf::from(name),
+            ImageFormat::Png,
+            DetectOptions::default(),
+            QueueOptions {
+                result_name: Some(name.to_string()),
+                priority,
+                callback_url: None,
+                quota_permit: None,
+            },
+        );
+    }
+
+    /// Result names of the next `count` items.
+    fn pop_names(queue: &ImageQueue, count: usize) -> Vec<String> {
+        iter::from_fn(|| queue.pop_next())
+            .take(count)
+            .map(|item| item.result_name)
+            .collect()
+    }
+
+    #[test]
+    fn takes_by_priority_then_upload_order() {
+        let queue = ImageQueue::new(None);
+        push(&queue, "low", Priority::Low);
+        push(&queue, "normal-1", Priority::Normal);
+        push(&queue, "high", Priority::High);
+        push(&queue, "normal-2", Priority::Normal);
+        assert_eq!(
+            pop_names(&queue, usize::MAX),
+            ["high", "normal-1", "normal-2", "low"]
+        );
+    }
+
+    #[test]
+    fn aging_does_not_reorder_stored_items() {
+        let queue = ImageQueue::new(Some(Duration::from_millis(1)));
+        push(&queue, "low-1", Priority::Low);
+        push(&queue, "low-2", Priority::Low);
+        thread::sleep(Duration::from_millis(5));
+        push(&queue, "normal", Priority::Normal);
+        // aged up to high, the low items waiting longest go first
+        assert_eq!(pop_names(&queue, 1), ["low-1"]);
+
+        push(&queue, "high", Priority::High);
+        assert_eq!(queue.pop_oldest().unwrap().result_name, "low-2");
+        assert_eq!(queue.pop_oldest().unwrap().result_name, "normal");
+        assert_eq!(pop_names(&queue, usize::MAX), ["high"]);
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::{Condvar, Mutex};
+
+/// Bounds how many inferences, including their resize and tensor buffers, run at once across the
+/// queue worker and the synchronous endpoints.
+pub struct InferenceLimit {
+    /// Unbounded when `None`, the in-flight count is still tracked for the stats.
+    max: Option<usize>,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Held for the duration of one inference, frees its slot when dropped.
+pub struct InferencePermit<'a> {
+    limit: &'a InferenceLimit,
+}
+
+impl InferenceLimit {
+    pub fn new(max: Option<usize>) -> InferenceLimit {
+        InferenceLimit {
+            max,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block the calling thread until a slot is free. Only call this from blocking threads.
+    pub fn acquire(&self) -> InferencePermit<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while self.max.is_some_and(|max| *in_flight >= max) {
+            in_flight = self.released.wait(in_flight).unwrap();
+        }
+        *in_flight += 1;
+        InferencePermit { limit: self }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+}
+
+impl Drop for InferencePermit<'_> {
+    fn drop(&mut self) {
+        *self.limit.in_flight.lock().unwrap() -= 1;
+        self.limit.released.notify_one();
+    }
+}
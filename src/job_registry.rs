@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+static MAX_JOBS: usize = 10000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Processing,
+    Done { count: usize },
+    Failed { reason: String },
+}
+
+impl JobStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Processing => "processing",
+            JobStatus::Done { .. } => "done",
+            JobStatus::Failed { .. } => "failed",
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, JobStatus::Done { .. } | JobStatus::Failed { .. })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JobState {
+    pub status: JobStatus,
+    pub added_time: Instant,
+    pub updated_time: Instant,
+}
+
+/// Status of the jobs pushed to the queue, finished jobs are evicted once `ttl` has passed.
+pub struct JobRegistry {
+    ttl: Duration,
+    jobs: Mutex<HashMap<Uuid, JobState>>,
+}
+
+impl JobRegistry {
+    pub fn new(ttl: Duration) -> JobRegistry {
+        JobRegistry {
+            ttl,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<JobState> {
+        match self.jobs.lock().unwrap().get(id) {
+            Some(job) if !self.is_expired(job) => Some(job.clone()),
+            _ => None,
+        }
+    }
+
+    /// Look up several jobs while taking the lock once.
+    pub fn get_many(&self, ids: &[Option<Uuid>]) -> Vec<Option<JobState>> {
+        let jobs = self.jobs.lock().unwrap();
+        ids.iter()
+            .map(|id| match id.and_then(|id| jobs.get(&id)) {
+                Some(job) if !self.is_expired(job) => Some(job.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Register a job as queued, unless the worker already picked it up.
+    pub fn insert(&self, id: Uuid) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= MAX_JOBS {
+            self.evict(&mut jobs);
+        }
+        let now = Instant::now();
+        jobs.entry(id).or_insert(JobState {
+            status: JobStatus::Queued,
+            added_time: now,
+            updated_time: now,
+        });
+    }
+
+    pub fn set_status(&self, id: &Uuid, status: JobStatus) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let now = Instant::now();
+        let job = jobs.entry(*id).or_insert(JobState {
+            status: JobStatus::Queued,
+            added_time: now,
+            updated_time: now,
+        });
+        job.status = status;
+        job.updated_time = now;
+    }
+
+    fn is_expired(&self, job: &JobState) -> bool {
+        job.status.is_terminal() && job.updated_time.elapsed() >= self.ttl
+    }
+
+    /// Drop expired jobs, then the oldest finished job if that did not make room.
+    /// Queued and processing jobs are never evicted, the queue itself bounds those.
+    fn evict(&self, jobs: &mut HashMap<Uuid, JobState>) {
+        jobs.retain(|_, job| !self.is_expired(job));
+        if jobs.len() >= MAX_JOBS {
+            let oldest = jobs
+                .iter()
+                .filter(|(_, job)| job.status.is_terminal())
+                .min_by_key(|(_, job)| job.updated_time)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                jobs.remove(&oldest);
+            }
+        }
+    }
+}
@@ -1,4 +1,34 @@
+pub mod analytics;
+pub mod anchors;
+pub mod annotate;
+pub mod audit_log;
+pub mod batch;
+pub mod callback;
 pub mod config;
+pub mod crops;
+pub mod detection;
+pub mod encode;
+pub mod error;
+pub mod export;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod idempotency;
 pub mod image_queue;
+pub mod inference_limit;
+pub mod job_registry;
+pub mod model_source;
+#[cfg(feature = "pdf")]
+pub mod pdf;
+pub mod proto;
+pub mod quality;
+pub mod quarantine;
 pub mod queue_processor;
+pub mod queue_quota;
+pub mod rate_limiter;
+pub mod result_file;
+pub mod result_name;
+pub mod stats;
+pub mod statsd;
 pub mod ultra_predictor;
+pub mod voc;
+pub mod worker_watchdog;
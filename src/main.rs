@@ -1,143 +1,2056 @@
-use actix_files;
-use actix_multipart::form::{tempfile::TempFile, MultipartForm};
+use actix_files::{self, NamedFile};
+use actix_multipart::form::{
+    bytes::Bytes,
+    tempfile::{TempFile, TempFileConfig},
+    MultipartForm, MultipartFormConfig,
+};
+use actix_rt::{task, time};
 use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    get,
+    http::{
+        header::{
+            ContentEncoding, ContentType, HeaderMap, HeaderName, HeaderValue, ACCEPT,
+            ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_DISPOSITION, RETRY_AFTER, VARY,
+        },
+        Method, StatusCode,
+    },
     post,
     web::{self},
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder,
+};
+use clap::{Arg, Command};
+use image::{imageops::FilterType, io::Reader, DynamicImage, ImageFormat};
+use mime::{self, Mime};
+use prost::Message;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Cursor, Seek},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
-use image::ImageFormat;
-use mime;
-use std::fs;
+use tokio::sync::mpsc;
+use url::Url;
+use uuid::Uuid;
 
+#[cfg(feature = "grpc")]
+use face_detection_server::grpc;
+#[cfg(feature = "pdf")]
+use face_detection_server::pdf::render_pages;
 use face_detection_server::{
-    config::Config, image_queue::ImageQueue, queue_processor::process_queue_task,
-    ultra_predictor::UltraPredictor,
+    analytics::Analytics,
+    annotate::{annotate, AnnotationStyle, BoxColors},
+    batch::run_batch,
+    callback::parse_callback_url,
+    config::{AcceptMode, Config, QueueFullPolicy, ResultNaming, ResultsService},
+    detection::{
+        decode_image, detect_any_face, detect_faces, detect_faces_at_thresholds, DetectOptions,
+        DetectionResult, Rotation, Selection, SpatialSort,
+    },
+    encode::{encode_image, OutputFormat},
+    error::Error,
+    export::{zip_results, ChannelWriter},
+    idempotency::{IdempotencyKeys, KeyState},
+    image_queue::{ImageQueue, Priority, QueueItem, QueueOptions},
+    job_registry::{JobRegistry, JobStatus},
+    proto,
+    queue_processor::{fail_taken_jobs, process_queue_task, write_error_result, TakenJobs},
+    queue_quota::QueueQuota,
+    rate_limiter::RateLimiter,
+    result_file::{is_compressed, read_result, result_path},
+    result_name::{check_name, content_hash},
+    stats::Stats,
+    statsd::StatsdClient,
+    ultra_predictor::{InferenceTimings, RawCandidate, UltraPredictor},
+    voc::voc_annotation,
+    worker_watchdog::WorkerWatchdog,
 };
 use serde::{Deserialize, Serialize};
 use std::{process, sync::Arc};
 
+// result files never change once written, so clients may cache them for a long time
+static RESULT_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+static NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+/// How often the queue depth and inferences in flight are pushed to StatsD.
+static STATSD_GAUGE_INTERVAL_SECS: u64 = 10;
+static PROTOBUF_CONTENT_TYPE: &str = "application/protobuf";
+static VOC_CONTENT_TYPE: &str = "application/xml";
+static API_KEY_HEADER: &str = "X-Api-Key";
+static IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+static DEADLINE_HEADER: &str = "X-Deadline-Ms";
+static INFERENCE_TIME_HEADER: &str = "x-inference-time-ms";
+static QUEUE_WAIT_HEADER: &str = "x-queue-wait-ms";
+static MAX_EXPORT_RESULTS: usize = 1000;
+/// Zip chunks, one per result, written ahead of the client downloading the export.
+static EXPORT_STREAM_BUFFER: usize = 4;
+/// Upload page served on `/` with `TEST_PAGE`, without external assets.
+static TEST_PAGE: &str = include_str!("test_page.html");
+static MAX_STATUS_IDS: usize = 1000;
+static MAX_THRESHOLDS: usize = 10;
+/// Same as the field limit of `SyncUpload`, the default in memory limit of forms is 2 MiB.
+static SYNC_UPLOAD_MEMORY_LIMIT: usize = 20 * 1024 * 1024;
+
+/// Upload to `/queue`, kept on disk until the worker gets to it.
 #[derive(MultipartForm)]
 pub struct Upload {
     #[multipart(limit = "20 MiB")]
     file: TempFile,
 }
 
+/// Upload to the synchronous endpoints, decoded straight from memory.
+#[derive(MultipartForm)]
+pub struct SyncUpload {
+    #[multipart(limit = "20 MiB")]
+    file: Bytes,
+}
+
 struct AppState {
+    config: Arc<Config>,
+    ultra_predictor: Arc<UltraPredictor>,
     queue: Arc<ImageQueue>,
+    stats: Arc<Stats>,
+    idempotency_keys: IdempotencyKeys,
+    jobs: Arc<JobRegistry>,
+    analytics: Arc<Analytics>,
+    /// Bounds the jobs in flight per client, `None` without `QUEUE_QUOTA`.
+    queue_quota: Option<QueueQuota>,
+    worker_watchdog: Arc<WorkerWatchdog>,
+}
+
+impl AppState {
+    /// Serialize `body` as a JSON response, pretty-printed when `PRETTY_JSON` is set.
+    fn json<T: Serialize>(&self, mut response: HttpResponseBuilder, body: &T) -> HttpResponse {
+        let body = match self.config.pretty_json {
+            true => serde_json::to_string_pretty(body),
+            false => serde_json::to_string(body),
+        };
+        match body {
+            Ok(body) => response.content_type(ContentType::json()).body(body),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct QueueResponse {
     id: Option<String>,
+    /// Results are available at `/result/{name}`, the id unless `RESULT_NAMING` says otherwise.
+    name: Option<String>,
     err: Option<String>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct ErrorResponse {
+    err: String,
+}
+
+#[derive(Deserialize)]
+struct DetectQuery {
+    rotate: Option<u16>,
+    roi: Option<String>,
+    multi_orientation: Option<bool>,
+    select: Option<String>,
+    tiled: Option<bool>,
+    sort: Option<String>,
+}
+
+impl DetectQuery {
+    fn to_options(&self, config: &Config) -> Result<DetectOptions, String> {
+        let rotation = match self.rotate {
+            Some(degrees) => Rotation::from_degrees(degrees)?,
+            None => None,
+        };
+
+        let roi = match &self.roi {
+            Some(roi) => Some(roi.parse()?),
+            None => None,
+        };
+
+        Ok(DetectOptions {
+            rotation,
+            roi,
+            multi_orientation: self.multi_orientation.unwrap_or(false),
+            select: match &self.select {
+                Some(name) => Some(Selection::from_name(name)?),
+                None => None,
+            },
+            tiling: self.tiled.unwrap_or(false).then_some(config.tiling),
+            spatial_sort: match self.sort.as_deref() {
+                Some("spatial") => Some(SpatialSort {
+                    row_tolerance: config.row_tolerance,
+                }),
+                None | Some("confidence") => None,
+                Some(sort) => {
+                    return Err(format!("sort must be confidence or spatial, got {}", sort));
+                }
+            },
+            deadline: None,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct QueueQuery {
+    name: Option<String>,
+    /// `high`, `normal` (default) or `low`.
+    priority: Option<String>,
+    callback_url: Option<String>,
+}
+
+impl QueueQuery {
+    fn priority(&self) -> Result<Priority, String> {
+        match &self.priority {
+            Some(priority) => Priority::from_name(priority),
+            None => Ok(Priority::Normal),
+        }
+    }
+
+    fn callback_url(&self, config: &Config) -> Result<Option<Url>, String> {
+        match &self.callback_url {
+            Some(callback_url) => Ok(Some(
+                parse_callback_url(callback_url, &config.callback_allowed_hosts)
+                    .map_err(str::to_string)?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
 #[post("/queue")]
 async fn add_to_queue(
+    req: HttpRequest,
     file_payload: MultipartForm<Upload>,
+    query: web::Query<DetectQuery>,
+    queue_query: web::Query<QueueQuery>,
     data: web::Data<AppState>,
 ) -> impl Responder {
     let temp_file = file_payload.0.file;
-    let content_type_opt = temp_file.content_type;
 
-    let content_type = match content_type_opt {
-        Some(content) => content,
-        None => {
-            return HttpResponse::BadRequest().json(QueueResponse {
-                id: None,
-                err: Some("content_type not specified".to_string()),
-            });
+    // a retried upload returns the job created by the first attempt, the key stays reserved
+    // until this upload is queued or rejected
+    let reservation = match idempotency_key(&req).map(|key| data.idempotency_keys.reserve(key)) {
+        Some(KeyState::New(reservation)) => Some(reservation),
+        Some(KeyState::Existing(id, result_name)) => {
+            let _ = temp_file.file.close();
+            return data.json(
+                HttpResponse::Ok(),
+                &QueueResponse {
+                    id: Some(id.to_string()),
+                    name: Some(result_name),
+                    err: None,
+                },
+            );
+        }
+        Some(KeyState::InProgress) => {
+            let _ = temp_file.file.close();
+            return data.json(
+                HttpResponse::Conflict(),
+                &QueueResponse {
+                    id: None,
+                    name: None,
+                    err: Some("a request with this Idempotency-Key is in progress".to_string()),
+                },
+            );
         }
+        None => None,
     };
 
-    match (content_type.type_(), content_type.subtype()) {
-        (mime::IMAGE, mime::PNG) => {}
-        (mime::IMAGE, mime::JPEG) => {}
-        _ => {
-            return HttpResponse::BadRequest().json(QueueResponse {
-                id: None,
-                err: Some("content_type not supported".to_string()),
-            });
+    let parsed = query.to_options(&data.config).and_then(|options| {
+        Ok((
+            options,
+            queue_query.priority()?,
+            queue_query.callback_url(&data.config)?,
+        ))
+    });
+    let (mut options, priority, callback_url) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            let _ = temp_file.file.close();
+            return data.json(
+                HttpResponse::BadRequest(),
+                &QueueResponse {
+                    id: None,
+                    name: None,
+                    err: Some(err),
+                },
+            );
         }
     };
 
-    let format = match ImageFormat::from_mime_type(content_type) {
-        Some(format) => format,
-        None => {
-            return HttpResponse::BadRequest().json(QueueResponse {
-                id: None,
-                err: Some("unable to find image format for content_type".to_string()),
-            });
+    options.deadline = request_deadline(&req);
+
+    let format = match validate_temp_file(&temp_file, &options) {
+        Ok(format) => format,
+        Err(err) => {
+            let _ = temp_file.file.close();
+            return data.json(
+                HttpResponse::BadRequest(),
+                &QueueResponse {
+                    id: None,
+                    name: None,
+                    err: Some(err.to_string()),
+                },
+            );
         }
     };
 
-    if temp_file.size < 1 {
-        let _ = temp_file.file.close();
-        return HttpResponse::BadRequest().json(QueueResponse {
-            id: None,
-            err: Some("file size is 0".to_string()),
-        });
-    }
+    let result_name = match result_name(&data, &temp_file, queue_query.name.as_deref()).await {
+        Ok(result_name) => result_name,
+        Err(err) => {
+            let _ = temp_file.file.close();
+            return data.json(
+                HttpResponse::BadRequest(),
+                &QueueResponse {
+                    id: None,
+                    name: None,
+                    err: Some(err.to_string()),
+                },
+            );
+        }
+    };
+
+    // the slot is held by the queued item and freed once the worker is done with it
+    let quota_permit = match &data.queue_quota {
+        Some(queue_quota) => match queue_quota.try_acquire(&client_key(&req)) {
+            Some(permit) => Some(permit),
+            None => {
+                let _ = temp_file.file.close();
+                return data.json(
+                    HttpResponse::TooManyRequests(),
+                    &QueueResponse {
+                        id: None,
+                        name: None,
+                        err: Some("queue quota exceeded".to_string()),
+                    },
+                );
+            }
+        },
+        None => None,
+    };
 
     if data.queue.is_full() {
-        let _ = temp_file.file.close();
-        return HttpResponse::ServiceUnavailable().json(QueueResponse {
-            id: None,
-            err: Some("queue is full".to_string()),
-        });
+        match data.config.queue_full_policy {
+            QueueFullPolicy::Reject => {
+                let _ = temp_file.file.close();
+                return data.json(
+                    HttpResponse::ServiceUnavailable(),
+                    &QueueResponse {
+                        id: None,
+                        name: None,
+                        err: Some("queue is full".to_string()),
+                    },
+                );
+            }
+            QueueFullPolicy::DropOldest => {
+                if let Some(dropped) = data.queue.pop_oldest() {
+                    drop_queue_item(&data, dropped);
+                }
+            }
+        }
     }
 
     let (_, path) = match temp_file.file.keep() {
         Ok(res) => res,
         Err(_) => {
-            return HttpResponse::InternalServerError().json(QueueResponse {
-                id: None,
-                err: Some("could not store file".to_string()),
+            return data.json(
+                HttpResponse::InternalServerError(),
+                &QueueResponse {
+                    id: None,
+                    name: None,
+                    err: Some("could not store file".to_string()),
+                },
+            );
+        }
+    };
+
+    let queue_options = QueueOptions {
+        result_name: result_name.clone(),
+        priority,
+        callback_url,
+        quota_permit,
+    };
+    let id = data.queue.push(path, format, options, queue_options);
+    let result_name = result_name.unwrap_or_else(|| id.to_string());
+    data.jobs.insert(id);
+    data.stats.record_enqueued();
+    if let Some(reservation) = reservation {
+        reservation.complete(id, result_name.clone());
+    }
+
+    return data.json(
+        HttpResponse::Created(),
+        &QueueResponse {
+            id: Some(id.to_string()),
+            name: Some(result_name),
+            err: None,
+        },
+    );
+}
+
+/// Result file name of an upload following `RESULT_NAMING`, `None` names it after the job id.
+async fn result_name(
+    data: &AppState,
+    temp_file: &TempFile,
+    name: Option<&str>,
+) -> Result<Option<String>, &'static str> {
+    match (data.config.result_naming, name) {
+        (ResultNaming::ClientName, Some(name)) => Ok(Some(check_name(name)?.to_string())),
+        (_, Some(_)) => Err("name requires RESULT_NAMING=client_name"),
+        (ResultNaming::ContentHash, None) => {
+            let path = temp_file.file.path().to_path_buf();
+            match web::block(move || content_hash(&path)).await {
+                Ok(Ok(hash)) => Ok(Some(hash)),
+                _ => Err("unable to hash upload"),
+            }
+        }
+        (_, None) => Ok(None),
+    }
+}
+
+#[derive(Serialize)]
+struct JobStatusResponse {
+    id: String,
+    /// `queued`, `processing`, `done`, `failed` or `not_found`
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// Status of several jobs at once, the body being a JSON array of job ids.
+#[post("/queue/status")]
+async fn get_queue_status(ids: web::Json<Vec<String>>, data: web::Data<AppState>) -> HttpResponse {
+    if ids.len() > MAX_STATUS_IDS {
+        return data.json(
+            HttpResponse::BadRequest(),
+            &ErrorResponse {
+                err: format!("at most {} ids per request", MAX_STATUS_IDS),
+            },
+        );
+    }
+
+    let uuids: Vec<Option<Uuid>> = ids.iter().map(|id| Uuid::parse_str(id).ok()).collect();
+    let statuses: Vec<JobStatusResponse> = ids
+        .iter()
+        .zip(data.jobs.get_many(&uuids))
+        .map(|(id, job)| {
+            let status = job.map(|job| job.status);
+            JobStatusResponse {
+                id: id.clone(),
+                status: status.as_ref().map_or("not_found", JobStatus::name),
+                count: match &status {
+                    Some(JobStatus::Done { count }) => Some(*count),
+                    _ => None,
+                },
+                reason: match status {
+                    Some(JobStatus::Failed { reason }) => Some(reason),
+                    _ => None,
+                },
+            }
+        })
+        .collect();
+
+    data.json(HttpResponse::Ok(), &statuses)
+}
+
+/// Fail a queued job evicted by `QueueFullPolicy::DropOldest` and delete its upload.
+fn drop_queue_item(data: &AppState, item: QueueItem) {
+    println!("queue is full, dropping job {}", item.id);
+    let reason = "dropped from full queue";
+    write_error_result(&data.config, &item, reason);
+    data.jobs.set_status(
+        &item.id,
+        JobStatus::Failed {
+            reason: reason.to_string(),
+        },
+    );
+    data.stats.record_failed();
+    if let Err(err) = fs::remove_file(&item.image_location) {
+        println!("unable to remove dropped temp file; {}", err);
+    }
+}
+
+/// Idempotency keys are scoped to the API key so clients can not collide with each other.
+fn idempotency_key(req: &HttpRequest) -> Option<String> {
+    let key = req.headers().get(IDEMPOTENCY_KEY_HEADER)?.to_str().ok()?;
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok())
+        .unwrap_or("");
+    Some(format!("{}:{}", api_key, key))
+}
+
+/// Point in time a request has to be answered by, from its `X-Deadline-Ms` header relative to
+/// when the request arrived. Stored in the request extensions by the timeout middleware.
+#[derive(Clone, Copy)]
+struct RequestDeadline(Instant);
+
+/// Time a synchronous detection waited for the blocking pool and spent detecting, stored in the
+/// request extensions by `detect_upload` for the `TIMING_HEADERS`.
+#[derive(Clone, Copy)]
+struct DetectionTiming {
+    queue_wait: Duration,
+    inference: Duration,
+}
+
+fn set_timing_headers(response: &mut ServiceResponse) {
+    let timing = response
+        .request()
+        .extensions()
+        .get::<DetectionTiming>()
+        .copied();
+    if let Some(timing) = timing {
+        let headers = response.headers_mut();
+        for (name, duration) in [
+            (INFERENCE_TIME_HEADER, timing.inference),
+            (QUEUE_WAIT_HEADER, timing.queue_wait),
+        ] {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from(duration.as_millis() as u64),
+            );
+        }
+    }
+}
+
+fn parse_deadline(req: &ServiceRequest) -> Result<Option<RequestDeadline>, String> {
+    let header = match req.headers().get(DEADLINE_HEADER) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    match header.to_str().ok().and_then(|ms| ms.parse::<u64>().ok()) {
+        Some(ms) => Ok(Some(RequestDeadline(
+            Instant::now() + Duration::from_millis(ms),
+        ))),
+        None => Err(format!(
+            "{} must be a number of milliseconds",
+            DEADLINE_HEADER
+        )),
+    }
+}
+
+fn request_deadline(req: &HttpRequest) -> Option<Instant> {
+    req.extensions()
+        .get::<RequestDeadline>()
+        .map(|deadline| deadline.0)
+}
+
+#[post("/detect")]
+async fn detect(
+    req: HttpRequest,
+    file_payload: MultipartForm<SyncUpload>,
+    query: web::Query<DetectQuery>,
+    debug_query: web::Query<DebugQuery>,
+    thresholds_query: web::Query<ThresholdsQuery>,
+    format_query: web::Query<ResultQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let profile = debug_query.profile.unwrap_or(false);
+    if debug_query.raw.unwrap_or(false) {
+        if !data.config.debug_raw_candidates {
+            return data.json(
+                HttpResponse::BadRequest(),
+                &ErrorResponse {
+                    err: "raw requires DEBUG_RAW_CANDIDATES".to_string(),
+                },
+            );
+        }
+        return detect_raw(&req, &data, file_payload.0.file, &query).await;
+    }
+    if let Some(thresholds) = &thresholds_query.thresholds {
+        let thresholds = match parse_thresholds(thresholds) {
+            Ok(thresholds) => thresholds,
+            Err(err) => return data.json(HttpResponse::BadRequest(), &ErrorResponse { err }),
+        };
+        if wants_voc(&format_query) || wants_protobuf(&req, &format_query) {
+            return data.json(
+                HttpResponse::BadRequest(),
+                &ErrorResponse {
+                    err: "thresholds results are only available as JSON".to_string(),
+                },
+            );
+        }
+        return detect_at_thresholds(
+            &req,
+            &data,
+            file_payload.0.file,
+            &query,
+            thresholds,
+            profile,
+        )
+        .await;
+    }
+
+    let file_name = file_payload.0.file.file_name.clone();
+    let (_, result, decode_time) =
+        match detect_upload(&req, &data, file_payload.0.file, &query, detect_faces).await {
+            Ok(detection) => detection,
+            Err(response) => return response,
+        };
+
+    // with ACCEPT_MODE=reject a result that is not accepted is a client error
+    let status = match (result.accepted, data.config.accept_mode) {
+        (Some(false), AcceptMode::Reject) => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::OK,
+    };
+    if wants_voc(&format_query) {
+        let file_name = file_name.as_deref().unwrap_or("image");
+        return HttpResponse::build(status)
+            .content_type(VOC_CONTENT_TYPE)
+            .body(voc_annotation(&result, file_name));
+    }
+    if wants_protobuf(&req, &format_query) {
+        return HttpResponse::build(status)
+            .content_type(PROTOBUF_CONTENT_TYPE)
+            .body(proto::DetectionResult::from(&result).encode_to_vec());
+    }
+    match profile {
+        true => data.json(
+            HttpResponse::build(status),
+            &ProfiledResult {
+                profile: Profile::new(decode_time, &result.timings),
+                result,
+            },
+        ),
+        false => data.json(HttpResponse::build(status), &result),
+    }
+}
+
+#[derive(Deserialize)]
+struct ThresholdsQuery {
+    /// Comma separated confidence thresholds, e.g. `0.3,0.5,0.7`.
+    thresholds: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ThresholdResults {
+    /// Detection result per threshold, keyed by the threshold.
+    thresholds: BTreeMap<String, DetectionResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<Profile>,
+}
+
+fn parse_thresholds(thresholds: &str) -> Result<Vec<f32>, String> {
+    let thresholds = thresholds
+        .split(',')
+        .map(|threshold| match threshold.trim().parse::<f32>() {
+            Ok(threshold) if (0.0..=1.0).contains(&threshold) => Ok(threshold),
+            _ => Err(format!(
+                "thresholds must be numbers from 0 to 1, got {}",
+                threshold
+            )),
+        })
+        .collect::<Result<Vec<f32>, String>>()?;
+    if thresholds.len() > MAX_THRESHOLDS {
+        return Err(format!("at most {} thresholds are allowed", MAX_THRESHOLDS));
+    }
+    // results are keyed by the threshold, `0.5` and `0.50` would share one
+    let mut keys = HashSet::new();
+    match thresholds
+        .iter()
+        .find(|threshold| !keys.insert(threshold.to_string()))
+    {
+        Some(duplicate) => Err(format!("threshold {} is listed more than once", duplicate)),
+        None => Ok(thresholds),
+    }
+}
+
+/// `/detect` with `?thresholds=`, running inference once and post processing it per threshold
+/// so tuning UIs can preview several thresholds from one request. `ACCEPT_MODE` applies to each
+/// result on its own and the response is never a 422, as some thresholds may find a clear face.
+async fn detect_at_thresholds(
+    req: &HttpRequest,
+    data: &AppState,
+    upload: Bytes,
+    query: &DetectQuery,
+    thresholds: Vec<f32>,
+    profile: bool,
+) -> HttpResponse {
+    let keys: Vec<String> = thresholds.iter().map(f32::to_string).collect();
+    let run_detection = move |ultra_predictor: &UltraPredictor,
+                              image: &DynamicImage,
+                              options: &DetectOptions,
+                              resize_filter: FilterType| {
+        detect_faces_at_thresholds(ultra_predictor, image, options, resize_filter, &thresholds)
+    };
+    let (_, results, decode_time) =
+        match detect_upload(req, data, upload, query, run_detection).await {
+            Ok(detection) => detection,
+            Err(response) => return response,
+        };
+
+    let profile = match (profile, results.first()) {
+        (true, Some(result)) => Some(Profile::new(decode_time, &result.timings)),
+        _ => None,
+    };
+    data.json(
+        HttpResponse::Ok(),
+        &ThresholdResults {
+            thresholds: keys.into_iter().zip(results).collect(),
+            profile,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct DebugQuery {
+    profile: Option<bool>,
+    /// Add every candidate before thresholding and NMS, needs `DEBUG_RAW_CANDIDATES`.
+    raw: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct RawResult {
+    /// The detections as `/detect` returns them.
+    result: DetectionResult,
+    /// Every candidate of the model on the whole region, without `?multi_orientation` or
+    /// `?tiled`, in pixels of the image.
+    raw_candidates: Vec<RawCandidate>,
+}
+
+/// `/detect?raw=true`, the result next to the candidates it was selected from.
+async fn detect_raw(
+    req: &HttpRequest,
+    data: &AppState,
+    upload: Bytes,
+    query: &DetectQuery,
+) -> HttpResponse {
+    let run_detection = |ultra_predictor: &UltraPredictor,
+                         image: &DynamicImage,
+                         options: &DetectOptions,
+                         resize_filter: FilterType| {
+        let result = detect_faces(ultra_predictor, image, options, resize_filter)?;
+        let mut raw_candidates =
+            ultra_predictor.raw_candidates(&options.region(image), resize_filter)?;
+        // map candidates from the region back to the whole image like the detections
+        if let Some(roi) = options.roi {
+            for RawCandidate { bbox, .. } in raw_candidates.iter_mut() {
+                let [x1, y1, x2, y2] = bbox;
+                *bbox = [*x1 + roi.x, *y1 + roi.y, *x2 + roi.x, *y2 + roi.y];
+            }
+        }
+        Ok(RawResult {
+            result,
+            raw_candidates,
+        })
+    };
+    match detect_upload(req, data, upload, query, run_detection).await {
+        Ok((_, raw_result, _)) => data.json(HttpResponse::Ok(), &raw_result),
+        Err(response) => response,
+    }
+}
+
+/// Milliseconds spent in each step of a `/detect` request.
+#[derive(Serialize)]
+struct Profile {
+    decode_ms: f64,
+    resize_ms: f64,
+    tensor_ms: f64,
+    inference_ms: f64,
+    post_process_ms: f64,
+}
+
+impl Profile {
+    fn new(decode_time: Duration, timings: &InferenceTimings) -> Profile {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        Profile {
+            decode_ms: millis(decode_time),
+            resize_ms: millis(timings.resize),
+            tensor_ms: millis(timings.tensor),
+            inference_ms: millis(timings.inference),
+            post_process_ms: millis(timings.post_process),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ProfiledResult {
+    #[serde(flatten)]
+    result: DetectionResult,
+    profile: Profile,
+}
+
+/// Detection result of one page of a pdf.
+#[cfg(feature = "pdf")]
+#[derive(Serialize)]
+struct PageResult {
+    /// 1 for the first page.
+    page: usize,
+    #[serde(flatten)]
+    result: DetectionResult,
+}
+
+#[cfg(feature = "pdf")]
+#[derive(Serialize)]
+struct PdfResult {
+    pages: Vec<PageResult>,
+    page_count: usize,
+    /// Pages past `PDF_MAX_PAGES` were not processed.
+    truncated: bool,
+}
+
+/// Render each page of an uploaded pdf and detect faces on it, up to `PDF_MAX_PAGES` pages.
+#[cfg(feature = "pdf")]
+#[post("/detect/pdf")]
+async fn detect_pdf(
+    req: HttpRequest,
+    file_payload: MultipartForm<SyncUpload>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let pdf = file_payload.0.file.data;
+    if !pdf.starts_with(b"%PDF-") {
+        return error_response(&data, Error::Validation("upload is not a pdf"));
+    }
+
+    let options = DetectOptions {
+        deadline: request_deadline(&req),
+        ..DetectOptions::default()
+    };
+    let ultra_predictor = data.ultra_predictor.clone();
+    let config = data.config.clone();
+    let detection = web::block(move || {
+        let rendered = render_pages(&pdf, config.pdf_max_pages)?;
+        let mut pages = Vec::new();
+        for (index, image) in rendered.pages.iter().enumerate() {
+            options.check_deadline()?;
+            let result = detect_faces(&ultra_predictor, image, &options, config.resize_filter)?;
+            pages.push(PageResult {
+                page: index + 1,
+                result,
             });
         }
+        Ok::<_, Error>(PdfResult {
+            truncated: rendered.page_count > pages.len(),
+            pages,
+            page_count: rendered.page_count,
+        })
+    })
+    .await;
+
+    match detection {
+        Ok(Ok(result)) => data.json(HttpResponse::Ok(), &result),
+        Ok(Err(err)) => error_response(&data, err),
+        Err(_) => data.json(
+            HttpResponse::InternalServerError(),
+            &ErrorResponse {
+                err: "detection failed".to_string(),
+            },
+        ),
+    }
+}
+
+#[cfg(not(feature = "pdf"))]
+#[post("/detect/pdf")]
+async fn detect_pdf(data: web::Data<AppState>) -> HttpResponse {
+    data.json(
+        HttpResponse::NotImplemented(),
+        &ErrorResponse {
+            err: "pdf support needs the server built with --features pdf".to_string(),
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct HasFaceQuery {
+    fast: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct HasFaceResponse {
+    has_face: bool,
+    /// Not counted in the fast path, which skips non-maximum-suppression.
+    count: Option<usize>,
+}
+
+#[post("/has-face")]
+async fn has_face(
+    req: HttpRequest,
+    file_payload: MultipartForm<SyncUpload>,
+    query: web::Query<DetectQuery>,
+    has_face_query: web::Query<HasFaceQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let upload = file_payload.0.file;
+    let response = match has_face_query.fast.unwrap_or(false) {
+        true => detect_upload(&req, &data, upload, &query, detect_any_face)
+            .await
+            .map(|(_, has_face, _)| HasFaceResponse {
+                has_face,
+                count: None,
+            }),
+        false => detect_upload(&req, &data, upload, &query, detect_faces)
+            .await
+            .map(|(_, result, _)| HasFaceResponse {
+                has_face: result.count > 0,
+                count: Some(result.count),
+            }),
+    };
+
+    match response {
+        Ok(response) => data.json(HttpResponse::Ok(), &response),
+        Err(response) => response,
+    }
+}
+
+#[derive(Serialize)]
+struct ValidateResponse {
+    valid: bool,
+    /// Size of the decoded image, after `?rotate` when given.
+    width: u32,
+    height: u32,
+}
+
+/// Run the checks and the decode of `/detect` without inference, so clients can pre-flight an
+/// upload. The upload is discarded either way, failures answer like `/detect` would.
+#[post("/validate")]
+async fn validate(
+    req: HttpRequest,
+    file_payload: MultipartForm<SyncUpload>,
+    query: web::Query<DetectQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let no_detection =
+        |_: &UltraPredictor, _: &DynamicImage, _: &DetectOptions, _: FilterType| Ok::<_, Error>(());
+    match detect_upload(&req, &data, file_payload.0.file, &query, no_detection).await {
+        Ok((image, _, _)) => data.json(
+            HttpResponse::Ok(),
+            &ValidateResponse {
+                valid: true,
+                width: image.width(),
+                height: image.height(),
+            },
+        ),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+struct AnnotateQuery {
+    colors: Option<String>,
+    labels: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ImageOutputQuery {
+    out: Option<String>,
+    quality: Option<u8>,
+}
+
+impl ImageOutputQuery {
+    /// Output format and quality of image responses. `?out=` takes precedence over the `Accept`
+    /// header, png when neither names a supported format.
+    fn to_output(&self, req: &HttpRequest, config: &Config) -> Result<(OutputFormat, u8), String> {
+        let format = match self.out.as_deref() {
+            Some(name) => {
+                OutputFormat::from_name(name).ok_or(format!("unsupported out {}", name))?
+            }
+            None => req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .and_then(OutputFormat::from_accept)
+                .unwrap_or(OutputFormat::Png),
+        };
+        let quality = self.quality.unwrap_or(config.jpeg_quality);
+        if !(1..=100).contains(&quality) {
+            return Err("quality must be between 1 and 100".to_string());
+        }
+
+        Ok((format, quality))
+    }
+}
+
+#[post("/annotate")]
+async fn annotate_upload(
+    req: HttpRequest,
+    file_payload: MultipartForm<SyncUpload>,
+    query: web::Query<DetectQuery>,
+    annotate_query: web::Query<AnnotateQuery>,
+    output_query: web::Query<ImageOutputQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let (output_format, quality) = match output_query.to_output(&req, &data.config) {
+        Ok(output) => output,
+        Err(err) => return data.json(HttpResponse::BadRequest(), &ErrorResponse { err }),
+    };
+
+    let colors = match annotate_query.colors.as_deref() {
+        None | Some("plain") => BoxColors::Plain,
+        Some("confidence") => BoxColors::Confidence,
+        Some(_) => {
+            return data.json(
+                HttpResponse::BadRequest(),
+                &ErrorResponse {
+                    err: "colors must be plain or confidence".to_string(),
+                },
+            );
+        }
+    };
+    let labels = annotate_query.labels.unwrap_or(false);
+    if labels && data.config.annotation_font.is_none() {
+        return data.json(
+            HttpResponse::BadRequest(),
+            &ErrorResponse {
+                err: "labels require ANNOTATION_FONT_PATH to be configured".to_string(),
+            },
+        );
+    }
+
+    let (image, result, _) =
+        match detect_upload(&req, &data, file_payload.0.file, &query, detect_faces).await {
+            Ok(detection) => detection,
+            Err(response) => return response,
+        };
+
+    let config = data.config.clone();
+    let encoded = web::block(move || {
+        let style = AnnotationStyle {
+            colors,
+            palette: &config.annotation_palette,
+            font: match labels {
+                true => config.annotation_font.as_ref(),
+                false => None,
+            },
+        };
+        let mut image = image.to_rgb8();
+        annotate(&mut image, &result.detections, &style);
+
+        encode_image(&DynamicImage::ImageRgb8(image), output_format, quality)
+    })
+    .await;
+
+    match encoded {
+        Ok(Ok(encoded)) => HttpResponse::Ok()
+            .content_type(output_format.content_type())
+            .insert_header((VARY, "Accept"))
+            .body(encoded),
+        _ => data.json(
+            HttpResponse::InternalServerError(),
+            &ErrorResponse {
+                err: "unable to encode image".to_string(),
+            },
+        ),
+    }
+}
+
+/// Validate and decode an upload, then run `run_detection` on the blocking pool. Shared by the
+/// synchronous endpoints, returns the oriented image detection ran on with its result and how
+/// long decoding took.
+async fn detect_upload<T, F>(
+    req: &HttpRequest,
+    data: &AppState,
+    upload: Bytes,
+    query: &DetectQuery,
+    run_detection: F,
+) -> Result<(DynamicImage, T, Duration), HttpResponse>
+where
+    T: Send + 'static,
+    F: FnOnce(&UltraPredictor, &DynamicImage, &DetectOptions, FilterType) -> Result<T, Error>
+        + Send
+        + 'static,
+{
+    let mut options = match query.to_options(&data.config) {
+        Ok(options) => options,
+        Err(err) => return Err(data.json(HttpResponse::BadRequest(), &ErrorResponse { err })),
+    };
+    options.deadline = request_deadline(req);
+
+    let format = match validate_upload(
+        upload.data.len(),
+        upload.content_type.as_ref(),
+        || Ok(Cursor::new(&upload.data[..])),
+        &options,
+    ) {
+        Ok(format) => format,
+        Err(err) => return Err(error_response(data, err)),
+    };
+
+    let ultra_predictor = data.ultra_predictor.clone();
+    let resize_filter = data.config.resize_filter;
+    let min_dimension = data.config.min_image_dimension;
+    let limits = data.config.decode_limits.clone();
+    let submitted = Instant::now();
+    let detection = web::block(move || {
+        let decode_start = Instant::now();
+        let queue_wait = decode_start - submitted;
+        let image = options.orient(decode_image(&upload.data, format, limits)?);
+        let decode_time = decode_start.elapsed();
+        options.check_size(&image, min_dimension)?;
+        // the blocking pool may have been busy for longer than the client waits
+        options.check_deadline()?;
+        let inference_start = Instant::now();
+        let result = run_detection(&ultra_predictor, &image, &options, resize_filter)?;
+        let timing = DetectionTiming {
+            queue_wait,
+            inference: inference_start.elapsed(),
+        };
+        Ok((image, result, decode_time, timing))
+    })
+    .await;
+
+    match detection {
+        Ok(Ok((image, result, decode_time, timing))) => {
+            req.extensions_mut().insert(timing);
+            Ok((image, result, decode_time))
+        }
+        Ok(Err(err)) => Err(error_response(data, err)),
+        Err(_) => Err(data.json(
+            HttpResponse::InternalServerError(),
+            &ErrorResponse {
+                err: "detection failed".to_string(),
+            },
+        )),
+    }
+}
+
+/// Bad uploads are the client's fault, anything else is reported without internals.
+fn error_response(data: &AppState, err: Error) -> HttpResponse {
+    let (status, message) = match &err {
+        Error::Decode(_) | Error::Validation(_) => (StatusCode::BAD_REQUEST, err.to_string()),
+        Error::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, err.to_string()),
+        Error::Inference(_) | Error::ModelOutput(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "inference failed".to_string(),
+        ),
+        Error::Config(_) | Error::Io(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "detection failed".to_string(),
+        ),
+    };
+    if status.is_server_error() {
+        println!("{}", err);
+    }
+    data.json(HttpResponse::build(status), &ErrorResponse { err: message })
+}
+
+/// `validate_upload` for uploads buffered to a temp file.
+fn validate_temp_file(temp_file: &TempFile, options: &DetectOptions) -> Result<ImageFormat, Error> {
+    validate_upload(
+        temp_file.size,
+        temp_file.content_type.as_ref(),
+        || File::open(temp_file.file.path()).map(BufReader::new),
+        options,
+    )
+}
+
+/// Checks shared by the upload endpoints, returns the image format of the upload. `open` reads
+/// the upload from the start, it is called again for each look at the image.
+fn validate_upload<R, F>(
+    size: usize,
+    content_type: Option<&Mime>,
+    open: F,
+    options: &DetectOptions,
+) -> Result<ImageFormat, Error>
+where
+    R: BufRead + Seek,
+    F: Fn() -> io::Result<R>,
+{
+    if size < 1 {
+        return Err(Error::Validation("file size is 0"));
+    }
+
+    let format = upload_format(content_type, || sniff_format(open().ok()?))?;
+
+    // only the header is read here, a truncated body is caught when decoding
+    let dimensions = match open() {
+        Ok(upload) => {
+            let mut reader = Reader::new(upload);
+            reader.set_format(format);
+            reader.into_dimensions().ok()
+        }
+        Err(_) => None,
+    };
+    let (width, height) = match dimensions {
+        Some(dimensions) => dimensions,
+        None => return Err(Error::Decode("corrupt or truncated image")),
     };
+    options.check_roi(width, height)?;
 
-    let id = data.queue.push(path, format);
+    Ok(format)
+}
 
-    return HttpResponse::Created().json(QueueResponse {
-        id: Some(id.to_string()),
-        err: None,
+/// Get the image format from the part content type, or by sniffing the magic bytes when the client
+/// did not send a content type.
+fn upload_format<F>(content_type: Option<&Mime>, sniff: F) -> Result<ImageFormat, Error>
+where
+    F: FnOnce() -> Option<ImageFormat>,
+{
+    match content_type {
+        Some(content_type) if *content_type != mime::APPLICATION_OCTET_STREAM => {
+            match (content_type.type_(), content_type.subtype()) {
+                (mime::IMAGE, mime::PNG) => {}
+                (mime::IMAGE, mime::JPEG) => {}
+                _ => return Err(Error::Validation("content_type not supported")),
+            };
+            ImageFormat::from_mime_type(content_type).ok_or(Error::Validation(
+                "unable to find image format for content_type",
+            ))
+        }
+        _ => match sniff() {
+            Some(format) => Ok(format),
+            None => Err(Error::Validation(
+                "content_type not specified and image format not supported",
+            )),
+        },
+    }
+}
+
+fn sniff_format<R: BufRead + Seek>(upload: R) -> Option<ImageFormat> {
+    let format = Reader::new(upload).with_guessed_format().ok()?.format()?;
+    match format {
+        ImageFormat::Png | ImageFormat::Jpeg => Some(format),
+        _ => None,
+    }
+}
+
+#[get("/stats")]
+async fn get_stats(data: web::Data<AppState>) -> impl Responder {
+    let inference_in_flight = data.ultra_predictor.inference_limit.in_flight();
+    let summary = data.stats.summary(data.queue.len(), inference_in_flight);
+    data.json(HttpResponse::Ok(), &summary)
+}
+
+/// Page for manually testing detection from a browser, disabled with `TEST_PAGE=false`.
+#[get("/")]
+async fn get_test_page(data: web::Data<AppState>) -> HttpResponse {
+    match data.config.test_page {
+        true => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(TEST_PAGE),
+        false => data.json(
+            HttpResponse::NotFound(),
+            &ErrorResponse {
+                err: "the test page is disabled".to_string(),
+            },
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    healthy: bool,
+    instance_id: String,
+    /// `false` once the queue worker died and used up `WORKER_MAX_RESTARTS`.
+    worker_alive: bool,
+    worker_restarts: u32,
+}
+
+/// 503 once inference kept failing after rebuilding the session, see `INFERENCE_FAILURE_LIMIT`,
+/// or the queue worker is dead, so orchestration restarts the server.
+#[get("/health")]
+async fn get_health(data: web::Data<AppState>) -> HttpResponse {
+    let worker_alive = data.worker_watchdog.is_alive();
+    let healthy = data.ultra_predictor.is_healthy() && worker_alive;
+    let response = match healthy {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    data.json(
+        response,
+        &HealthResponse {
+            healthy,
+            instance_id: data.config.instance_id.clone(),
+            worker_alive,
+            worker_restarts: data.worker_watchdog.restarts(),
+        },
+    )
+}
+
+/// Queue based autoscaling signal, see `SCALE_TARGET_LATENCY_MS`.
+#[get("/scale")]
+async fn get_scale(data: web::Data<AppState>) -> impl Responder {
+    let signal = data
+        .stats
+        .scale(data.queue.len(), data.config.scale_target_latency);
+    data.json(HttpResponse::Ok(), &signal)
+}
+
+/// Aggregates over the stored results as of the last background scan.
+#[get("/analytics")]
+async fn get_analytics(data: web::Data<AppState>) -> HttpResponse {
+    match data.analytics.summary() {
+        Some(summary) => data.json(HttpResponse::Ok(), &summary),
+        None => data.json(
+            HttpResponse::ServiceUnavailable(),
+            &ErrorResponse {
+                err: "analytics are not computed yet".to_string(),
+            },
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResultQuery {
+    format: Option<String>,
+}
+
+#[get("/result/{id}")]
+async fn get_result(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<ResultQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    // accept both `/result/{id}` and the `/result/{id}.json` paths of the static service
+    let id = id.into_inner();
+    // results are named after the job id or, with `RESULT_NAMING`, a hash or client name
+    let name = match check_name(id.strip_suffix(".json").unwrap_or(&id)) {
+        Ok(name) => name,
+        Err(_) => {
+            return data.json(
+                HttpResponse::BadRequest(),
+                &ErrorResponse {
+                    err: "id is not a valid result name".to_string(),
+                },
+            );
+        }
+    };
+
+    let result_path = result_path(&data.config.results_dir, name);
+    if wants_voc(&query) {
+        return get_result_voc(result_path, name.to_string(), &data).await;
+    }
+    if wants_ndjson(&req, &query) {
+        return get_result_ndjson(result_path, &data).await;
+    }
+    let compressed = is_compressed(&result_path);
+    if compressed && !accepts_gzip(&req) {
+        return get_result_decompressed(result_path, &data).await;
+    }
+
+    match NamedFile::open_async(result_path).await {
+        Ok(file) => {
+            let file = match compressed {
+                true => file
+                    .set_content_type(mime::APPLICATION_JSON)
+                    .set_content_encoding(ContentEncoding::Gzip)
+                    .disable_content_disposition(),
+                false => file,
+            };
+            let mut response = file
+                .use_etag(true)
+                .use_last_modified(true)
+                .into_response(&req);
+            response
+                .headers_mut()
+                .insert(VARY, HeaderValue::from_static("Accept, Accept-Encoding"));
+            set_result_cache_control(response.status(), response.headers_mut());
+            response
+        }
+        Err(_) => data.json(
+            HttpResponse::NotFound(),
+            &ErrorResponse {
+                err: "result not found".to_string(),
+            },
+        ),
+    }
+}
+
+/// Crop of a single face of a completed job, stored by the worker when `CROPS_DIR` is set.
+#[get("/result/{job_id}/face/{face_id}")]
+async fn get_face_crop(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let (job_id, face_id) = path.into_inner();
+    let not_found = |err: &str| {
+        data.json(
+            HttpResponse::NotFound(),
+            &ErrorResponse {
+                err: err.to_string(),
+            },
+        )
+    };
+
+    let crops = match &data.config.crops {
+        Some(crops) => crops,
+        None => return not_found("face crops are not kept, see CROPS_DIR"),
+    };
+    let id = match Uuid::parse_str(&job_id) {
+        Ok(id) if data.jobs.get(&id).is_some() => id,
+        _ => return not_found("job not found"),
+    };
+    let crop_path = match crops.path(&id, &face_id) {
+        Some(crop_path) => crop_path,
+        None => return not_found("face not found"),
+    };
+    match NamedFile::open_async(crop_path).await {
+        Ok(file) => file.into_response(&req),
+        Err(_) => not_found("face not found"),
+    }
+}
+
+/// `?format=protobuf` or an `Accept` header asking for protobuf, JSON stays the default.
+fn wants_protobuf(req: &HttpRequest, query: &ResultQuery) -> bool {
+    if let Some(format) = &query.format {
+        return format == "protobuf";
+    }
+    match req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+    {
+        Some(accept) => accept.contains(PROTOBUF_CONTENT_TYPE),
+        None => false,
+    }
+}
+
+fn wants_ndjson(req: &HttpRequest, query: &ResultQuery) -> bool {
+    if let Some(format) = &query.format {
+        return format == "ndjson";
+    }
+    match req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+    {
+        Some(accept) => accept.contains(NDJSON_CONTENT_TYPE),
+        None => false,
+    }
+}
+
+/// `?format=voc`, Pascal VOC XML. Only asked for explicitly, unlike the formats with an `Accept`
+/// header of their own.
+fn wants_voc(query: &ResultQuery) -> bool {
+    query.format.as_deref() == Some("voc")
+}
+
+/// Whether the client takes a gzip encoded response, to serve compressed results as they are.
+fn accepts_gzip(req: &HttpRequest) -> bool {
+    match req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|accept| accept.to_str().ok())
+    {
+        Some(accept) => accept.contains("gzip"),
+        None => false,
+    }
+}
+
+/// A result stored with `COMPRESS_RESULTS` for clients that do not accept gzip.
+async fn get_result_decompressed(result_path: PathBuf, data: &AppState) -> HttpResponse {
+    let result = match web::block(move || read_result(&result_path)).await {
+        Ok(Ok(result)) => result,
+        _ => {
+            return data.json(
+                HttpResponse::NotFound(),
+                &ErrorResponse {
+                    err: "result not found".to_string(),
+                },
+            );
+        }
+    };
+
+    let mut response = HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header((VARY, "Accept, Accept-Encoding"))
+        .body(result);
+    set_result_cache_control(response.status(), response.headers_mut());
+    response
+}
+
+/// Convert a stored result to a Pascal VOC annotation named after the result, error results of
+/// failed jobs are returned as they are.
+async fn get_result_voc(result_path: PathBuf, name: String, data: &AppState) -> HttpResponse {
+    let result = match web::block(move || read_result(&result_path)).await {
+        Ok(Ok(result)) => result,
+        _ => {
+            return data.json(
+                HttpResponse::NotFound(),
+                &ErrorResponse {
+                    err: "result not found".to_string(),
+                },
+            );
+        }
+    };
+
+    let mut response = match serde_json::from_slice::<DetectionResult>(&result) {
+        Ok(result) => HttpResponse::Ok()
+            .content_type(VOC_CONTENT_TYPE)
+            .body(voc_annotation(&result, &name)),
+        Err(_) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(result),
+    };
+    set_result_cache_control(response.status(), response.headers_mut());
+    response
+}
+
+/// Emit the detections of a result one JSON value per line instead of a single array.
+async fn get_result_ndjson(result_path: PathBuf, data: &AppState) -> HttpResponse {
+    let result = match web::block(move || read_result(&result_path)).await {
+        Ok(Ok(result)) => result,
+        _ => {
+            return data.json(
+                HttpResponse::NotFound(),
+                &ErrorResponse {
+                    err: "result not found".to_string(),
+                },
+            );
+        }
+    };
+
+    let result: serde_json::Value = match serde_json::from_slice(&result) {
+        Ok(result) => result,
+        Err(_) => {
+            return data.json(
+                HttpResponse::InternalServerError(),
+                &ErrorResponse {
+                    err: "unable to read result".to_string(),
+                },
+            );
+        }
+    };
+
+    // error results have no detections and are returned as a single line
+    let mut body = String::new();
+    match result.get("detections") {
+        Some(serde_json::Value::Array(detections)) => {
+            for detection in detections {
+                body.push_str(&detection.to_string());
+                body.push('\n');
+            }
+        }
+        _ => {
+            body.push_str(&result.to_string());
+            body.push('\n');
+        }
+    }
+
+    let mut response = HttpResponse::Ok()
+        .content_type(NDJSON_CONTENT_TYPE)
+        .insert_header((VARY, "Accept"))
+        .body(body);
+    set_result_cache_control(response.status(), response.headers_mut());
+    response
+}
+
+#[get("/admin/config")]
+async fn get_admin_config(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    // without ADMIN_API_KEY the admin endpoints do not exist
+    let admin_api_key = match &data.config.admin_api_key {
+        Some(admin_api_key) => admin_api_key,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok());
+    if api_key != Some(admin_api_key.as_str()) {
+        return data.json(
+            HttpResponse::Unauthorized(),
+            &ErrorResponse {
+                err: "invalid api key".to_string(),
+            },
+        );
+    }
+
+    data.json(HttpResponse::Ok(), &data.config.effective())
+}
+
+#[get("/model/info")]
+async fn get_model_info(data: web::Data<AppState>) -> impl Responder {
+    data.json(HttpResponse::Ok(), &data.ultra_predictor.info())
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    ids: String,
+    /// `voc` to export Pascal VOC annotations instead of the JSON results.
+    format: Option<String>,
+}
+
+/// Bundle the results of a batch, `?ids=` being a comma separated list of result names.
+#[get("/results/export")]
+async fn export_results(query: web::Query<ExportQuery>, data: web::Data<AppState>) -> HttpResponse {
+    let mut names = Vec::new();
+    for id in query
+        .ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+    {
+        match check_name(id) {
+            Ok(name) => names.push(name.to_string()),
+            Err(_) => {
+                return data.json(
+                    HttpResponse::BadRequest(),
+                    &ErrorResponse {
+                        err: format!("{} is not a valid result name", id),
+                    },
+                );
+            }
+        }
+    }
+    if names.is_empty() || names.len() > MAX_EXPORT_RESULTS {
+        return data.json(
+            HttpResponse::BadRequest(),
+            &ErrorResponse {
+                err: format!("ids must list 1 to {} results", MAX_EXPORT_RESULTS),
+            },
+        );
+    }
+
+    // the status is sent before the archive, so missing results are answered up front
+    let results_dir = data.config.results_dir.clone();
+    if !names
+        .iter()
+        .all(|name| result_path(&results_dir, name).exists())
+    {
+        return data.json(
+            HttpResponse::NotFound(),
+            &ErrorResponse {
+                err: "result not found".to_string(),
+            },
+        );
+    }
+
+    let voc = query.format.as_deref() == Some("voc");
+    let (sender, receiver) = mpsc::channel(EXPORT_STREAM_BUFFER);
+    task::spawn_blocking(move || {
+        let zipped = zip_results(
+            ChannelWriter::new(sender.clone()),
+            &results_dir,
+            &names,
+            voc,
+        )
+        .and_then(ChannelWriter::finish);
+        if let Err(err) = zipped {
+            println!("unable to export results; {}", err);
+            // fails the response, the client must not take a truncated archive for a whole one
+            let _ = sender.blocking_send(Err(err));
+        }
     });
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((CONTENT_DISPOSITION, "attachment; filename=\"results.zip\""))
+        .body(ZipStream { receiver })
+}
+
+/// Body of `/results/export`, the zip archive as `zip_results` writes it.
+struct ZipStream {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+}
+
+impl MessageBody for ZipStream {
+    type Error = io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<web::Bytes, io::Error>>> {
+        self.get_mut()
+            .receiver
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(web::Bytes::from)))
+    }
+}
+
+/// Only found results are cacheable, a missing result may still be written by the worker.
+fn set_result_cache_control(status: StatusCode, headers: &mut HeaderMap) {
+    if status == StatusCode::OK || status == StatusCode::NOT_MODIFIED {
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static(RESULT_CACHE_CONTROL),
+        );
+    }
+}
+
+/// Client a queued job is counted against for `QUEUE_QUOTA`, its API key or, without one, its IP.
+fn client_key(req: &HttpRequest) -> String {
+    match req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok())
+    {
+        Some(api_key) => format!("key:{}", api_key),
+        None => match req.peer_addr() {
+            Some(addr) => format!("ip:{}", addr.ip()),
+            None => "unknown".to_string(),
+        },
+    }
+}
+
+/// Uploads are limited per API key, or per client IP when no key is sent.
+fn check_rate_limit(
+    rate_limiter: Option<&RateLimiter>,
+    req: &ServiceRequest,
+) -> Result<(), HttpResponse> {
+    let rate_limiter = match rate_limiter {
+        Some(rate_limiter) if req.method() == Method::POST => rate_limiter,
+        _ => return Ok(()),
+    };
+
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok());
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    match rate_limiter.check(api_key, ip) {
+        Ok(_) => Ok(()),
+        Err(retry_after) => Err(HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string()))
+            .json(ErrorResponse {
+                err: "rate limit exceeded".to_string(),
+            })),
+    }
+}
+
+/// Reject uploads to a full queue before their body is read. `add_to_queue` checks again, as the
+/// queue may fill while an upload is received.
+fn check_queue_capacity(req: &ServiceRequest) -> Result<(), HttpResponse> {
+    let data = match req.app_data::<web::Data<AppState>>() {
+        Some(data) if req.method() == Method::POST && req.path() == "/queue" => data,
+        _ => return Ok(()),
+    };
+    if data.config.queue_full_policy != QueueFullPolicy::Reject || !data.queue.is_full() {
+        return Ok(());
+    }
+    // a retried upload still gets the job created by its first attempt
+    let retried =
+        idempotency_key(req.request()).is_some_and(|key| data.idempotency_keys.contains(&key));
+    match retried {
+        true => Ok(()),
+        false => Err(data.json(
+            HttpResponse::ServiceUnavailable(),
+            &QueueResponse {
+                id: None,
+                name: None,
+                err: Some("queue is full".to_string()),
+            },
+        )),
+    }
+}
+
+/// Command line options and the env variables they override.
+static ARG_ENV_VARS: [(&str, &str); 5] = [
+    ("model-path", "ULTRA_MODEL_PATH"),
+    ("threads", "ULTRA_THREADS"),
+    ("bind", "BIND_ADDRESS"),
+    ("port", "PORT"),
+    ("results-dir", "RESULTS_DIR"),
+];
+
+fn cli() -> Command {
+    let option = |id: &'static str, value_name: &'static str, help: &'static str| {
+        Arg::new(id).long(id).value_name(value_name).help(help)
+    };
+    Command::new("face-detection-server")
+        .about("Face detection server, options override the env variables of the same setting")
+        .arg(option(
+            "model-path",
+            "PATH",
+            "onnx model, overrides ULTRA_MODEL_PATH",
+        ))
+        .arg(option(
+            "threads",
+            "N",
+            "inference threads, overrides ULTRA_THREADS",
+        ))
+        .arg(option(
+            "bind",
+            "ADDRESS",
+            "address to listen on, overrides BIND_ADDRESS",
+        ))
+        .arg(option("port", "PORT", "port to listen on, overrides PORT"))
+        .arg(option(
+            "results-dir",
+            "DIR",
+            "results directory, overrides RESULTS_DIR",
+        ))
+        .subcommand(
+            Command::new("batch")
+                .about("Detect faces in a directory of images without starting the server")
+                .arg(option("input", "DIR", "directory of images").required(true))
+                .arg(option("output", "DIR", "directory results are written to").required(true)),
+        )
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let config: Config = Config::new();
-    let ultra_predictor = Arc::new(
-        UltraPredictor::new(&config.ultra_model_path, &config.ultra_threads).unwrap_or_else(
-            |ort_err| {
+    // arguments are applied as env variables, so they take precedence over the env and `.env`
+    let matches = cli().get_matches();
+    for (arg, env_var) in ARG_ENV_VARS {
+        if let Some(value) = matches.get_one::<String>(arg) {
+            std::env::set_var(env_var, value);
+        }
+    }
+
+    let config = Arc::new(Config::new());
+    let ultra_predictor = Arc::new(UltraPredictor::new(&config).unwrap_or_else(|ort_err| {
+        println!(
+            "Problem creating ultra onnx session: {}",
+            ort_err.to_string()
+        );
+        process::exit(1)
+    }));
+
+    if let Some(batch) = matches.subcommand_matches("batch") {
+        let input_dir = batch.get_one::<String>("input").map(PathBuf::from).unwrap();
+        let output_dir = batch
+            .get_one::<String>("output")
+            .map(PathBuf::from)
+            .unwrap();
+        let summary =
+            run_batch(&ultra_predictor, &config, &input_dir, &output_dir).unwrap_or_else(|err| {
+                println!("batch failed: {}", err);
+                process::exit(1)
+            });
+        println!(
+            "processed {} images, {} failed, {} skipped",
+            summary.processed, summary.failed, summary.skipped
+        );
+        process::exit(if summary.failed > 0 { 1 } else { 0 });
+    }
+
+    let queue = Arc::new(ImageQueue::new(config.priority_aging));
+    let statsd = config.statsd_address.as_ref().map(|address| {
+        StatsdClient::connect(address, &config.statsd_prefix, config.statsd_tags.clone())
+            .unwrap_or_else(|err| {
                 println!(
-                    "Problem creating ultra onnx session: {}",
-                    ort_err.to_string()
+                    "Unable to connect to the StatsD agent at {}: {}",
+                    address, err
                 );
                 process::exit(1)
-            },
-        ),
-    );
-    let queue = Arc::new(ImageQueue::new());
+            })
+    });
+    let stats = Arc::new(Stats::new(statsd));
+    let jobs = Arc::new(JobRegistry::new(config.job_ttl));
+    let analytics = Arc::new(Analytics::new());
+    let worker_watchdog = Arc::new(WorkerWatchdog::new(
+        config.worker_max_restarts,
+        config.worker_restart_backoff,
+    ));
 
     let app_state = web::Data::new(AppState {
+        config: config.clone(),
+        ultra_predictor: ultra_predictor.clone(),
         queue: queue.clone(),
+        stats: stats.clone(),
+        idempotency_keys: IdempotencyKeys::new(config.idempotency_window),
+        jobs: jobs.clone(),
+        analytics: analytics.clone(),
+        queue_quota: config.queue_quota.map(QueueQuota::new),
+        worker_watchdog: worker_watchdog.clone(),
     });
 
-    let _ = fs::create_dir("./results");
+    let _ = fs::create_dir_all(&config.results_dir);
+    let results_dir = config.results_dir.clone();
+    let bind_address = (config.bind_address.clone(), config.port);
+    let results_service = config.results_service;
+    let request_timeout = config.request_timeout;
+    let timing_headers = config.timing_headers;
+    let (http_workers, max_connections) = (config.http_workers, config.max_connections);
+    let temp_file_config = match &config.upload_dir {
+        Some(upload_dir) => TempFileConfig::default().directory(upload_dir),
+        None => TempFileConfig::default(),
+    };
+    let rate_limiter = config
+        .rate_limit
+        .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit, config.rate_limit_keys.clone())));
 
-    actix_rt::spawn(
-        async move { process_queue_task(ultra_predictor.clone(), queue.clone()).await },
-    );
+    #[cfg(feature = "grpc")]
+    if config.grpc_enabled {
+        let (ultra_predictor, config) = (ultra_predictor.clone(), config.clone());
+        actix_rt::spawn(async move {
+            if let Err(err) = grpc::serve_grpc(ultra_predictor, config).await {
+                println!("gRPC server failed; {}", err);
+            }
+        });
+    }
+
+    if stats.statsd.is_some() {
+        let (stats, queue, ultra_predictor) =
+            (stats.clone(), queue.clone(), ultra_predictor.clone());
+        actix_rt::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(STATSD_GAUGE_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                if let Some(statsd) = &stats.statsd {
+                    statsd.gauge("queue_depth", queue.len() as u64);
+                    let in_flight = ultra_predictor.inference_limit.in_flight();
+                    statsd.gauge("inference_in_flight", in_flight as u64);
+                }
+            }
+        });
+    }
 
-    HttpServer::new(move || {
-        App::new()
+    let (analytics_refresh, analytics_max_results) =
+        (config.analytics_refresh, config.analytics_max_results);
+    let analytics_dir = config.results_dir.clone();
+    actix_rt::spawn(async move {
+        let mut interval = time::interval(analytics_refresh);
+        loop {
+            interval.tick().await;
+            let (analytics, results_dir) = (analytics.clone(), analytics_dir.clone());
+            let refresh =
+                web::block(move || analytics.refresh(&results_dir, analytics_max_results)).await;
+            if let Ok(Err(err)) = refresh {
+                println!("unable to scan results for analytics; {}", err);
+            }
+        }
+    });
+
+    actix_rt::spawn(async move {
+        let taken = Arc::new(TakenJobs::default());
+        worker_watchdog
+            .supervise(
+                || {
+                    process_queue_task(
+                        ultra_predictor.clone(),
+                        queue.clone(),
+                        stats.clone(),
+                        jobs.clone(),
+                        config.clone(),
+                        taken.clone(),
+                    )
+                },
+                || fail_taken_jobs(&taken, &config, &jobs, &stats),
+            )
+            .await
+    });
+
+    let server = HttpServer::new(move || {
+        let rate_limiter = rate_limiter.clone();
+        let app = App::new()
+            .wrap_fn(move |req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    if timing_headers {
+                        set_timing_headers(&mut response);
+                    }
+                    Ok(response)
+                }
+            })
+            // runs before the handler extracts the multipart upload, so a rejected upload is
+            // never written to a temp file
+            .wrap_fn(move |req, srv| {
+                let check = check_rate_limit(rate_limiter.as_deref(), &req)
+                    .and_then(|_| check_queue_capacity(&req));
+                let call = match check {
+                    Ok(_) => Ok(srv.call(req)),
+                    Err(response) => Err(req.into_response(response)),
+                };
+                async move {
+                    match call {
+                        Ok(call) => call.await,
+                        Err(response) => Ok(response),
+                    }
+                }
+            })
+            // dropping a timed out request also drops its partially received upload, while
+            // decoding or inference already running on the blocking pool finishes on its own.
+            // A client deadline shortens the timeout and is checked again before inference.
+            .wrap_fn(move |req, srv| {
+                let http_req = req.request().clone();
+                let (timeout, call) = match parse_deadline(&req) {
+                    Ok(deadline) => {
+                        let timeout = match deadline {
+                            Some(deadline) => request_timeout
+                                .min(deadline.0.saturating_duration_since(Instant::now())),
+                            None => request_timeout,
+                        };
+                        if let Some(deadline) = deadline {
+                            req.extensions_mut().insert(deadline);
+                        }
+                        (timeout, Ok(srv.call(req)))
+                    }
+                    Err(err) => (
+                        request_timeout,
+                        Err(req
+                            .into_response(HttpResponse::BadRequest().json(ErrorResponse { err }))),
+                    ),
+                };
+                async move {
+                    let call = match call {
+                        Ok(call) => call,
+                        Err(response) => return Ok(response),
+                    };
+                    match time::timeout(timeout, call).await {
+                        Ok(response) => response,
+                        Err(_) => Ok(ServiceResponse::new(
+                            http_req,
+                            HttpResponse::GatewayTimeout().json(ErrorResponse {
+                                err: "request timed out".to_string(),
+                            }),
+                        )),
+                    }
+                }
+            })
             .app_data(app_state.clone())
+            .app_data(temp_file_config.clone())
+            .app_data(MultipartFormConfig::default().memory_limit(SYNC_UPLOAD_MEMORY_LIMIT))
             .service(add_to_queue)
-            .service(actix_files::Files::new("/result", "./results"))
-    })
-    .bind(("127.0.0.1", 8082))?
-    .run()
-    .await
+            .service(get_queue_status)
+            .service(detect_pdf)
+            .service(detect)
+            .service(annotate_upload)
+            .service(has_face)
+            .service(validate)
+            .service(get_health)
+            .service(get_stats)
+            .service(get_scale)
+            .service(get_analytics)
+            // before the results service, whose static `/result` scope would match it otherwise
+            .service(get_face_crop)
+            .service(export_results)
+            .service(get_model_info)
+            .service(get_admin_config)
+            .service(get_test_page);
+        match results_service {
+            ResultsService::Static => app.service(
+                web::scope("/result")
+                    .wrap_fn(|req, srv| {
+                        let response = srv.call(req);
+                        async move {
+                            let mut response = response.await?;
+                            set_result_cache_control(response.status(), response.headers_mut());
+                            Ok(response)
+                        }
+                    })
+                    .service(
+                        actix_files::Files::new("", &results_dir)
+                            .use_etag(true)
+                            .use_last_modified(true),
+                    ),
+            ),
+            ResultsService::Handler => app.service(get_result),
+        }
+    });
+    let server = match http_workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match max_connections {
+        Some(max_connections) => server.max_connections(max_connections),
+        None => server,
+    };
+    server.bind(bind_address)?.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_thresholds_are_rejected() {
+        assert_eq!(
+            parse_thresholds("0.3, 0.5,0.7").unwrap(),
+            vec![0.3, 0.5, 0.7]
+        );
+        assert!(parse_thresholds("0.5,0.50").is_err());
+        assert!(parse_thresholds("0.3,1.5").is_err());
+    }
 }
@@ -0,0 +1,114 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+
+static DOWNLOAD_CONNECT_TIMEOUT_SECS: u64 = 30;
+/// A download fails once it stalls this long, not when the whole model takes longer.
+static DOWNLOAD_READ_TIMEOUT_SECS: u64 = 60;
+
+/// The model built into the binary with the `embedded-model` feature, from the file
+/// `ULTRA_EMBEDDED_MODEL_PATH` (an absolute path) points to at build time.
+#[cfg(feature = "embedded-model")]
+pub static EMBEDDED_MODEL: Option<&[u8]> = Some(include_bytes!(env!("ULTRA_EMBEDDED_MODEL_PATH")));
+#[cfg(not(feature = "embedded-model"))]
+pub static EMBEDDED_MODEL: Option<&[u8]> = None;
+
+/// Where `UltraPredictor` loads the onnx model from.
+#[derive(Clone)]
+pub enum ModelSource {
+    File(PathBuf),
+    /// Model bytes embedded in the binary, i.e. `EMBEDDED_MODEL`.
+    Memory(&'static [u8]),
+}
+
+impl fmt::Display for ModelSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelSource::File(path) => write!(f, "{}", path.to_string_lossy()),
+            ModelSource::Memory(bytes) => write!(f, "memory ({} bytes)", bytes.len()),
+        }
+    }
+}
+
+impl ModelSource {
+    /// File name of the model, `embedded` for a model in memory.
+    pub fn file_name(&self) -> String {
+        match self {
+            ModelSource::File(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.to_string_lossy().to_string()),
+            ModelSource::Memory(_) => "embedded".to_string(),
+        }
+    }
+
+    /// First 16 hex digits of the sha256 of the model, identifying the exact model file.
+    pub fn digest(&self) -> io::Result<String> {
+        let hash = match self {
+            ModelSource::File(path) => Sha256::digest(fs::read(path)?),
+            ModelSource::Memory(bytes) => Sha256::digest(bytes),
+        };
+        Ok(format!("{:x}", hash)[..16].to_string())
+    }
+}
+
+/// Name the model downloaded from `url` is cached under. It is derived from the url without its
+/// query, so a changed url downloads the model again while a changed signature or token does not.
+pub fn cache_file_name(url: &str) -> String {
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    format!("model-{}.onnx", &hash[..16])
+}
+
+/// Download the model at `url` to `cache_path`, unless it was already downloaded before.
+pub fn fetch_model(url: &str, cache_path: &Path) -> io::Result<PathBuf> {
+    if cache_path.exists() {
+        println!("using cached model {}", cache_path.to_string_lossy());
+        return Ok(cache_path.to_path_buf());
+    }
+
+    println!("downloading model from {}", url);
+    let response = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(DOWNLOAD_CONNECT_TIMEOUT_SECS))
+        .timeout_read(Duration::from_secs(DOWNLOAD_READ_TIMEOUT_SECS))
+        .build()
+        .get(url)
+        .call()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // download next to the cache path first, so an interrupted download is never used as the model
+    let download_path = cache_path.with_extension("download");
+    let mut file = File::create(&download_path)?;
+    io::copy(&mut response.into_reader(), &mut file)?;
+    file.sync_all()?;
+    fs::rename(&download_path, cache_path)?;
+
+    Ok(cache_path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_file_name_follows_the_url_without_its_query() {
+        let v1 = cache_file_name("https://models.example.com/v1/model.onnx");
+        let v2 = cache_file_name("https://models.example.com/v2/model.onnx");
+        assert_ne!(v1, v2);
+        assert_eq!(
+            cache_file_name("https://models.example.com/v1/model.onnx?signature=abc&expires=1"),
+            v1
+        );
+        assert!(!cache_file_name("https://models.example.com/models/").is_empty());
+        assert!(v1.ends_with(".onnx") && !v1.contains(['/', '?']));
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::Mutex;
+
+use image::{DynamicImage, RgbaImage};
+use pdfium_render::prelude::*;
+
+use crate::error::Error;
+
+/// Longest side of a rendered page in pixels, enough for the portraits on a scanned page.
+static PDF_RENDER_SIZE: i32 = 2000;
+
+/// pdfium is not thread safe, so documents are rendered one at a time.
+static PDFIUM: Mutex<()> = Mutex::new(());
+
+pub struct RenderedPages {
+    /// The first pages of the document, up to the page limit.
+    pub pages: Vec<DynamicImage>,
+    /// Pages in the document, including those past the limit.
+    pub page_count: usize,
+}
+
+/// Render the first `max_pages` pages of a pdf with the pdfium library of the system.
+pub fn render_pages(pdf: &[u8], max_pages: usize) -> Result<RenderedPages, Error> {
+    let _pdfium = PDFIUM.lock().unwrap();
+    let bindings = Pdfium::bind_to_system_library()
+        .map_err(|err| Error::Config(format!("unable to load pdfium; {}", err)))?;
+    let pdfium = Pdfium::new(bindings);
+    let document = pdfium
+        .load_pdf_from_byte_slice(pdf, None)
+        .map_err(|_| Error::Decode("corrupt or encrypted pdf"))?;
+
+    let render_config = PdfRenderConfig::new()
+        .set_target_width(PDF_RENDER_SIZE)
+        .set_maximum_height(PDF_RENDER_SIZE);
+    let pages = document.pages();
+    let rendered = pages
+        .iter()
+        .take(max_pages)
+        .map(|page| {
+            let bitmap = page
+                .render_with_config(&render_config)
+                .map_err(|_| Error::Decode("unable to render pdf page"))?;
+            // built from the raw pixels, pdfium-render may use another version of `image`
+            let (width, height) = (bitmap.width() as u32, bitmap.height() as u32);
+            RgbaImage::from_raw(width, height, bitmap.as_rgba_bytes())
+                .map(DynamicImage::ImageRgba8)
+                .ok_or(Error::Decode("unable to render pdf page"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(RenderedPages {
+        pages: rendered,
+        page_count: pages.len() as usize,
+    })
+}
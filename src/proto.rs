@@ -0,0 +1,104 @@
+use crate::detection;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ImageSize {
+    #[prost(uint32, tag = "1")]
+    pub width: u32,
+    #[prost(uint32, tag = "2")]
+    pub height: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Detection {
+    #[prost(uint32, tag = "1")]
+    pub x_top_left: u32,
+    #[prost(uint32, tag = "2")]
+    pub y_top_left: u32,
+    #[prost(uint32, tag = "3")]
+    pub x_bottom_right: u32,
+    #[prost(uint32, tag = "4")]
+    pub y_bottom_right: u32,
+    #[prost(float, tag = "5")]
+    pub confidence: f32,
+    #[prost(string, tag = "6")]
+    pub id: String,
+}
+
+/// Protobuf encoding of the result envelope, `DetectionResult` of `proto/detection.proto`. The
+/// messages are written out instead of generated so building does not need `protoc`, keep their
+/// tags in sync with the `.proto` file.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DetectionResult {
+    #[prost(uint32, tag = "1")]
+    pub schema_version: u32,
+    #[prost(message, optional, tag = "2")]
+    pub image: Option<ImageSize>,
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+    #[prost(message, repeated, tag = "4")]
+    pub detections: Vec<Detection>,
+    #[prost(string, tag = "5")]
+    pub model_id: String,
+    #[prost(string, tag = "6")]
+    pub model_version: String,
+    #[prost(bool, optional, tag = "7")]
+    pub accepted: Option<bool>,
+    #[prost(string, tag = "8")]
+    pub reason: String,
+    #[prost(string, tag = "9")]
+    pub instance_id: String,
+    #[prost(string, tag = "10")]
+    pub quality_warning: String,
+    #[prost(string, tag = "11")]
+    pub thumbnail: String,
+    #[prost(uint32, optional, tag = "12")]
+    pub face_count: Option<u32>,
+    #[prost(float, optional, tag = "13")]
+    pub faces_per_megapixel: Option<f32>,
+    #[prost(bool, optional, tag = "14")]
+    pub crowded: Option<bool>,
+}
+
+impl From<&detection::DetectionResult> for DetectionResult {
+    fn from(result: &detection::DetectionResult) -> DetectionResult {
+        DetectionResult {
+            schema_version: result.schema_version,
+            image: Some(ImageSize {
+                width: result.image.width,
+                height: result.image.height,
+            }),
+            count: result.count as u32,
+            detections: result
+                .detections
+                .iter()
+                .enumerate()
+                .map(|(i, ([x1, y1, x2, y2], confidence))| Detection {
+                    x_top_left: *x1,
+                    y_top_left: *y1,
+                    x_bottom_right: *x2,
+                    y_bottom_right: *y2,
+                    confidence: *confidence,
+                    id: result.detection_ids.get(i).cloned().unwrap_or_default(),
+                })
+                .collect(),
+            model_id: result
+                .model
+                .as_ref()
+                .map(|model| model.id.clone())
+                .unwrap_or_default(),
+            model_version: result
+                .model
+                .as_ref()
+                .map(|model| model.version.clone())
+                .unwrap_or_default(),
+            accepted: result.accepted,
+            reason: result.reason.clone().unwrap_or_default(),
+            instance_id: result.instance_id.clone().unwrap_or_default(),
+            quality_warning: result.quality_warning.clone().unwrap_or_default(),
+            thumbnail: result.thumbnail.clone().unwrap_or_default(),
+            face_count: result.face_count.map(|count| count as u32),
+            faces_per_megapixel: result.faces_per_megapixel,
+            crowded: result.crowded,
+        }
+    }
+}
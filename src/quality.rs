@@ -0,0 +1,51 @@
+use image::DynamicImage;
+
+use crate::{config::AcceptMode, error::Error};
+
+/// Size the image is shrunk to before its luminance is measured, plenty for a mean and spread.
+static QUALITY_SAMPLE_SIZE: u32 = 256;
+
+/// Pre-check of the image brightness and contrast, detection quality drops badly on very dark or
+/// washed-out images.
+#[derive(Clone, Copy)]
+pub struct QualityCheck {
+    /// Minimum mean luminance, 0 to 255.
+    pub min_brightness: Option<f32>,
+    /// Minimum standard deviation of the luminance, 0 to 127.5.
+    pub min_contrast: Option<f32>,
+    /// `Flag` adds a `quality_warning` to the result, `Reject` fails detection.
+    pub mode: AcceptMode,
+}
+
+impl QualityCheck {
+    /// `low_light` or `low_contrast` when the image is below a threshold, an error instead with
+    /// `AcceptMode::Reject`.
+    pub fn check(&self, image: &DynamicImage) -> Result<Option<&'static str>, Error> {
+        let (brightness, contrast) = luminance_stats(image);
+        let warning = match (self.min_brightness, self.min_contrast) {
+            (Some(min_brightness), _) if brightness < min_brightness => "low_light",
+            (_, Some(min_contrast)) if contrast < min_contrast => "low_contrast",
+            _ => return Ok(None),
+        };
+        match (self.mode, warning) {
+            (AcceptMode::Flag, _) => Ok(Some(warning)),
+            (AcceptMode::Reject, "low_light") => Err(Error::Validation("image is too dark")),
+            (AcceptMode::Reject, _) => Err(Error::Validation("image has too little contrast")),
+        }
+    }
+}
+
+/// Mean and standard deviation of the luminance of `image`.
+fn luminance_stats(image: &DynamicImage) -> (f32, f32) {
+    let luma = image
+        .thumbnail(QUALITY_SAMPLE_SIZE, QUALITY_SAMPLE_SIZE)
+        .to_luma8();
+    let count = luma.pixels().len().max(1) as f64;
+    let mean = luma.pixels().map(|pixel| pixel[0] as f64).sum::<f64>() / count;
+    let variance = luma
+        .pixels()
+        .map(|pixel| (pixel[0] as f64 - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    (mean as f32, variance.sqrt() as f32)
+}
@@ -0,0 +1,57 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use uuid::Uuid;
+
+/// Directory uploads are moved to instead of being deleted, bounded by age and file count. Used
+/// for the quarantine of uploads that failed to decode and the archive of processed uploads.
+pub struct Quarantine {
+    pub dir: PathBuf,
+    pub max_files: usize,
+    pub ttl: Duration,
+}
+
+impl Quarantine {
+    /// Move `image_location` into the quarantine as `{id}.{extension}`, first making room by
+    /// removing files older than the ttl and then the oldest files above `max_files`.
+    pub fn store(&self, image_location: &Path, id: &Uuid, extension: &str) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        self.clean()?;
+
+        let destination = self.dir.join(format!("{}.{}", id, extension));
+        // temp files usually live on another filesystem, where rename does not work
+        if fs::rename(image_location, &destination).is_err() {
+            fs::copy(image_location, &destination)?;
+            fs::remove_file(image_location)?;
+        }
+        Ok(destination)
+    }
+
+    fn clean(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            match age >= self.ttl {
+                true => fs::remove_file(entry.path())?,
+                false => files.push((modified, entry.path())),
+            }
+        }
+
+        // keep room for the file being stored
+        if files.len() >= self.max_files {
+            files.sort();
+            let excess = files.len() + 1 - self.max_files;
+            for (_, path) in files.into_iter().take(excess) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
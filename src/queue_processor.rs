@@ -1,85 +1,518 @@
 use std::{
-    fs::{self, File},
-    io::{BufWriter, Write},
-    path::{Path, PathBuf},
+    collections::{HashMap, VecDeque},
+    fs, io, iter,
+    path::PathBuf,
     process,
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use actix_rt::time;
-use image::{io::Reader, imageops::FilterType};
+use actix_rt::{
+    task::{self, JoinHandle},
+    time,
+};
+use image::{DynamicImage, ImageFormat};
+use serde::Serialize;
+use url::Url;
+use uuid::Uuid;
 
-use crate::{image_queue::ImageQueue, ultra_predictor::{UltraPredictor, ULTRA_INPUT_WIDTH, ULTRA_INPUT_HEIGHT}};
+use crate::{
+    callback::send_callback,
+    config::{Config, ResultWriteMode},
+    detection::{detect_faces, load_image, DetectionResult},
+    error::Error,
+    image_queue::{ImageQueue, QueueItem},
+    job_registry::{JobRegistry, JobStatus},
+    result_file::{self, COMPRESSED_EXTENSION},
+    stats::Stats,
+    ultra_predictor::UltraPredictor,
+};
 
 static POLL_INTERVAL_MS: u64 = 10;
 
-pub async fn process_queue_task(ultra_predictor: Arc<UltraPredictor>, queue: Arc<ImageQueue>) {
+/// The oriented image, kept when face crops are stored, and its detections.
+type Inferred = (Option<DynamicImage>, DetectionResult);
+
+/// A queued upload whose decode runs on the blocking pool ahead of inference.
+type Decoding<'a> = (
+    QueueItem,
+    JoinHandle<Result<DynamicImage, Error>>,
+    Taken<'a>,
+);
+
+/// What is needed to fail a job the worker took off the queue.
+struct TakenJob {
+    result_name: String,
+    callback_url: Option<Url>,
+    image_location: PathBuf,
+}
+
+/// Jobs the queue worker took off the queue and did not finish yet. A worker that panicked
+/// leaves its jobs here for `fail_taken_jobs`.
+#[derive(Default)]
+pub struct TakenJobs {
+    jobs: Mutex<HashMap<Uuid, TakenJob>>,
+}
+
+/// A job held in `TakenJobs` while the worker works on it. Dropping it removes the job, unless
+/// the worker is unwinding from a panic.
+struct Taken<'a> {
+    taken: &'a TakenJobs,
+    id: Uuid,
+}
+
+impl TakenJobs {
+    fn take(&self, item: &QueueItem) -> Taken<'_> {
+        let job = TakenJob {
+            result_name: item.result_name.clone(),
+            callback_url: item.callback_url.clone(),
+            image_location: item.image_location.clone(),
+        };
+        self.jobs.lock().unwrap().insert(item.id, job);
+        Taken {
+            taken: self,
+            id: item.id,
+        }
+    }
+}
+
+impl Drop for Taken<'_> {
+    fn drop(&mut self) {
+        // the worker task is dropped while its panic unwinds
+        if !thread::panicking() {
+            self.taken.jobs.lock().unwrap().remove(&self.id);
+        }
+    }
+}
+
+/// Jobs whose decode was started ahead of inference, in queue order.
+struct DecodeAhead<T> {
+    started: VecDeque<T>,
+    /// `DECODE_AHEAD`, 0 decodes each job only once the previous one is done.
+    ahead: usize,
+}
+
+impl<T> DecodeAhead<T> {
+    fn new(ahead: usize) -> DecodeAhead<T> {
+        DecodeAhead {
+            started: VecDeque::new(),
+            ahead,
+        }
+    }
+
+    /// The oldest started job, after starting up to `ahead` more of `items` with `start`. `start`
+    /// returns `None` for a job it failed instead of starting.
+    fn next<I: Iterator>(
+        &mut self,
+        items: &mut I,
+        mut start: impl FnMut(I::Item) -> Option<T>,
+    ) -> Option<T> {
+        while self.started.len() <= self.ahead {
+            let item = match items.next() {
+                Some(item) => item,
+                None => break,
+            };
+            if let Some(started) = start(item) {
+                self.started.push_back(started);
+            }
+        }
+        self.started.pop_front()
+    }
+}
+
+/// Works off the queue in two stages: uploads are decoded on the blocking pool up to
+/// `DECODE_AHEAD` jobs ahead, while the oldest decoded job is in inference. Results are still
+/// written in queue order.
+pub async fn process_queue_task(
+    ultra_predictor: Arc<UltraPredictor>,
+    queue: Arc<ImageQueue>,
+    stats: Arc<Stats>,
+    jobs: Arc<JobRegistry>,
+    config: Arc<Config>,
+    taken: Arc<TakenJobs>,
+) {
     let mut interval = time::interval(Duration::from_millis(POLL_INTERVAL_MS));
+    // A timed out inference keeps running on the blocking pool while holding the session lock.
+    // Keep its handle so we wait for it instead of piling up more blocked inference threads.
+    let mut stuck_inference: Option<JoinHandle<Result<Inferred, Error>>> = None;
 
     loop {
         interval.tick().await;
-        for item in queue.drain() {
-            let image_location = item.image_location;
+        // taken one at a time as the previous ones are started, so a higher priority upload
+        // arriving meanwhile is next
+        let mut items =
+            iter::from_fn(|| queue.pop_next()).take(config.drain_limit.unwrap_or(usize::MAX));
+        let mut decoding: DecodeAhead<Decoding> = DecodeAhead::new(config.decode_ahead);
+        loop {
+            // start decoding the next jobs while the previous one is in inference
+            let next = decoding.next(&mut items, |item| {
+                let taken = taken.take(&item);
+                jobs.set_status(&item.id, JobStatus::Processing);
 
-            let mut image_buf = match Reader::open(image_location.clone()) {
-                Ok(image_buf) => image_buf,
-                Err(_) => {
-                    println!("Unable to open_image");
-                    remove_temp_file(image_location.clone());
-                    continue;
+                // the client gave up waiting while the item was queued
+                if let Err(error) = item.options.check_deadline() {
+                    let reason = error.to_string();
+                    println!("skipping inference; {}", reason);
+                    write_error_result(&config, &item, &reason);
+                    jobs.set_status(&item.id, JobStatus::Failed { reason });
+                    stats.record_failed();
+                    remove_temp_file(item.image_location.clone());
+                    return None;
                 }
+
+                let decode = start_decode(&item, &config);
+                Some((item, decode, taken))
+            });
+            let (item, decode, _taken) = match next {
+                Some(next) => next,
+                None => break,
             };
+            let image_location = item.image_location.clone();
 
-            image_buf.set_format(item.format);
-            let raw_image = match image_buf.decode() {
-                Ok(raw_image) => raw_image,
-                Err(_) => {
-                    println!("unable to decode image");
-                    remove_temp_file(image_location.clone());
+            let image = match finish_decode(&item, &config, decode).await {
+                Ok(image) => image,
+                Err(reason) => {
+                    jobs.set_status(&item.id, JobStatus::Failed { reason });
+                    stats.record_failed();
+                    quarantine_temp_file(&config, image_location.clone(), &item.id, item.format);
                     continue;
                 }
             };
 
-            let image = raw_image.resize_to_fill(
-                ULTRA_INPUT_WIDTH as u32,
-                ULTRA_INPUT_HEIGHT as u32,
-                FilterType::Triangle).to_rgb8();
+            if let Err(error) = item.options.check_size(&image, config.min_image_dimension) {
+                let reason = error.to_string();
+                println!("skipping inference; {}", reason);
+                write_error_result(&config, &item, &reason);
+                jobs.set_status(&item.id, JobStatus::Failed { reason });
+                stats.record_failed();
+                remove_temp_file(image_location.clone());
+                continue;
+            }
 
-            let res = ultra_predictor.run(&image).unwrap();
-
-            let results_folder = Path::new("./results");
-            let file = match File::create(results_folder.join(item.id.to_string() + ".json")) {
-                Ok(file) => file,
-                Err(_) => {
-                    println!("unable to create result file");
-                    remove_temp_file(image_location.clone());
-                    continue;
+            if let Some(stuck) = stuck_inference.as_mut() {
+                match time::timeout(config.inference_timeout, stuck).await {
+                    Ok(_) => stuck_inference = None,
+                    Err(_) => {
+                        println!("previous inference is still running");
+                        write_error_result(&config, &item, "inference timed out");
+                        jobs.set_status(
+                            &item.id,
+                            JobStatus::Failed {
+                                reason: "inference timed out".to_string(),
+                            },
+                        );
+                        stats.record_failed();
+                        remove_temp_file(image_location.clone());
+                        continue;
+                    }
                 }
-            };
+            }
+
+            // checked again as the deadline may pass while the job is decoded or waits on the
+            // previous inference
+            if let Err(error) = item.options.check_deadline() {
+                let reason = error.to_string();
+                println!("skipping inference; {}", reason);
+                write_error_result(&config, &item, &reason);
+                jobs.set_status(&item.id, JobStatus::Failed { reason });
+                stats.record_failed();
+                remove_temp_file(image_location.clone());
+                continue;
+            }
 
-            let mut writer = BufWriter::new(file);
+            let inference_start = Instant::now();
+            let predictor = ultra_predictor.clone();
+            let (options, resize_filter) = (item.options.clone(), config.resize_filter);
+            let keep_image = config.crops.is_some();
+            let mut inference = task::spawn_blocking(move || {
+                let image = options.orient(image);
+                let result = detect_faces(&predictor, &image, &options, resize_filter)?;
+                Ok((keep_image.then_some(image), result))
+            });
+            let (image, mut res) =
+                match time::timeout(config.inference_timeout, &mut inference).await {
+                    Ok(Ok(Ok(inferred))) => inferred,
+                    Ok(Ok(Err(err))) => {
+                        println!("{}", err);
+                        write_error_result(&config, &item, "inference failed");
+                        jobs.set_status(
+                            &item.id,
+                            JobStatus::Failed {
+                                reason: "inference failed".to_string(),
+                            },
+                        );
+                        stats.record_failed();
+                        remove_temp_file(image_location.clone());
+                        continue;
+                    }
+                    Ok(Err(err)) => {
+                        println!("inference task failed; {}", err);
+                        write_error_result(&config, &item, "inference failed");
+                        jobs.set_status(
+                            &item.id,
+                            JobStatus::Failed {
+                                reason: "inference failed".to_string(),
+                            },
+                        );
+                        stats.record_failed();
+                        remove_temp_file(image_location.clone());
+                        continue;
+                    }
+                    Err(_) => {
+                        println!("inference timed out after {:?}", config.inference_timeout);
+                        stuck_inference = Some(inference);
+                        write_error_result(&config, &item, "inference timed out");
+                        jobs.set_status(
+                            &item.id,
+                            JobStatus::Failed {
+                                reason: "inference timed out".to_string(),
+                            },
+                        );
+                        stats.record_failed();
+                        remove_temp_file(image_location.clone());
+                        continue;
+                    }
+                };
+            stats.record_inference_time(inference_start.elapsed());
+            res.assign_ids(&item.id.to_string());
 
             // TODO: also store some more info about the processing-job
-            match serde_json::to_writer(&mut writer, &res.bboxes_with_confidences) {
+            let has_face = res
+                .detections
+                .iter()
+                .any(|(_, confidence)| *confidence >= config.result_min_confidence);
+            let written = match (config.result_write_mode, has_face) {
+                (ResultWriteMode::FacesOnly, false) => Ok(()),
+                _ => write_result(&config, &item.result_name, &res),
+            };
+            match written {
                 Ok(_) => {}
-                Err(_) => {
-                    println!("unable to write result");
+                Err(err) => {
+                    println!("unable to write result; {}", err);
+                    call_back(
+                        &config,
+                        &item.id,
+                        item.callback_url.as_ref(),
+                        &ErrorResult {
+                            error: "unable to write result",
+                        },
+                    );
+                    jobs.set_status(
+                        &item.id,
+                        JobStatus::Failed {
+                            reason: "unable to write result".to_string(),
+                        },
+                    );
+                    stats.record_failed();
                     remove_temp_file(image_location.clone());
                     continue;
                 }
             };
 
-            match writer.flush() {
-                Ok(_) => {}
-                Err(_) => {
-                    println!("unable to write result");
-                    remove_temp_file(image_location.clone());
-                    continue;
+            // crops are stored before the job is done, so they exist once clients see it done
+            if let Some(image) = image {
+                let (config, id) = (config.clone(), item.id);
+                let (detections, ids) = (res.detections.clone(), res.detection_ids.clone());
+                let stored = task::spawn_blocking(move || match &config.crops {
+                    Some(crops) => crops.store(&id, &image, &detections, &ids),
+                    None => Ok(()),
+                });
+                match stored.await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => println!("unable to store face crops; {}", err),
+                    Err(err) => println!("face crop task failed; {}", err),
                 }
-            };
+            }
 
-            remove_temp_file(image_location.clone())
+            if let Some(audit_log) = &config.audit_log {
+                let confidences = res.detections.iter().map(|(_, confidence)| *confidence);
+                if let Err(err) =
+                    audit_log.record(&item.id, &item.result_name, confidences.collect())
+                {
+                    println!("unable to write audit log; {}", err);
+                }
+            }
+
+            call_back(&config, &item.id, item.callback_url.as_ref(), &res);
+            jobs.set_status(&item.id, JobStatus::Done { count: res.count });
+            stats.record_processed();
+            archive_temp_file(&config, image_location.clone(), &item.id, item.format)
+        }
+    }
+}
+
+/// Start decoding the upload of `item` on the blocking pool.
+fn start_decode(item: &QueueItem, config: &Config) -> JoinHandle<Result<DynamicImage, Error>> {
+    // decoding is CPU bound as well, keep it off the async runtime
+    let load_location = item.image_location.clone();
+    let (format, limits) = (item.format, config.decode_limits.clone());
+    task::spawn_blocking(move || load_image(&load_location, format, limits))
+}
+
+/// Wait for the upload of `item` to be decoded. When it can not be, its error result is written
+/// and the error returned.
+async fn finish_decode(
+    item: &QueueItem,
+    config: &Config,
+    decode: JoinHandle<Result<DynamicImage, Error>>,
+) -> Result<DynamicImage, String> {
+    match decode.await {
+        Ok(Ok(image)) => Ok(image),
+        Ok(Err(error)) => {
+            let reason = error.to_string();
+            println!("{}", reason);
+            write_error_result(config, item, &reason);
+            Err(reason)
+        }
+        Err(err) => {
+            println!("image loading task failed; {}", err);
+            write_error_result(config, item, "unable to load image");
+            Err("unable to load image".to_string())
+        }
+    }
+}
+
+/// Result written instead of the detections when a job fails, i.e. `{"error":"corrupt or truncated image"}`.
+#[derive(Serialize)]
+pub struct ErrorResult<'a> {
+    pub error: &'a str,
+}
+
+/// Write `{name}.json`, or `{name}.json.gz` with `COMPRESS_RESULTS`, atomically, readers polling
+/// the results directory only ever see complete files. The temp file is hidden so the static
+/// results service does not serve it.
+fn write_result<T: Serialize>(config: &Config, name: &str, result: &T) -> io::Result<()> {
+    let results_dir = &config.results_dir;
+    let temp_path = results_dir.join(format!(".{}.json.tmp", name));
+    result_file::write_result(
+        &temp_path,
+        result,
+        config.pretty_json,
+        config.compress_results,
+    )?;
+    let (path, stale_path) = match config.compress_results {
+        true => (
+            name.to_string() + COMPRESSED_EXTENSION,
+            name.to_string() + ".json",
+        ),
+        false => (
+            name.to_string() + ".json",
+            name.to_string() + COMPRESSED_EXTENSION,
+        ),
+    };
+    fs::rename(&temp_path, results_dir.join(path))?;
+    // a result written before `COMPRESS_RESULTS` changed would otherwise shadow this one
+    match fs::remove_file(results_dir.join(stale_path)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Write the error result of a failed job and send it to the job's callback.
+pub fn write_error_result(config: &Config, item: &QueueItem, error: &str) {
+    write_job_error(
+        config,
+        &item.id,
+        &item.result_name,
+        item.callback_url.as_ref(),
+        error,
+    );
+}
+
+fn write_job_error(
+    config: &Config,
+    id: &Uuid,
+    result_name: &str,
+    callback_url: Option<&Url>,
+    error: &str,
+) {
+    let result = ErrorResult { error };
+    match write_result(config, result_name, &result) {
+        Ok(_) => {}
+        Err(err) => println!("unable to write error result; {}", err),
+    }
+    call_back(config, id, callback_url, &result);
+}
+
+/// Fail the jobs a panicked worker left in `taken`, they would otherwise stay `processing`. Jobs
+/// it already finished keep their result.
+pub fn fail_taken_jobs(taken: &TakenJobs, config: &Config, jobs: &JobRegistry, stats: &Stats) {
+    let reason = "queue worker crashed";
+    for (id, job) in taken.jobs.lock().unwrap().drain() {
+        if jobs
+            .get(&id)
+            .is_some_and(|state| state.status.is_terminal())
+        {
+            continue;
+        }
+        println!("failing job {} taken by the crashed queue worker", id);
+        write_job_error(
+            config,
+            &id,
+            &job.result_name,
+            job.callback_url.as_ref(),
+            reason,
+        );
+        jobs.set_status(
+            &id,
+            JobStatus::Failed {
+                reason: reason.to_string(),
+            },
+        );
+        stats.record_failed();
+        // the worker may have removed it before it panicked
+        match fs::remove_file(&job.image_location) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                println!("unable to remove temp file; {}", err)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// POST `result` to `callback_url`, if the job has one, without waiting for the receiver.
+fn call_back<T: Serialize>(config: &Config, id: &Uuid, callback_url: Option<&Url>, result: &T) {
+    let url = match callback_url {
+        Some(url) => url.clone(),
+        None => return,
+    };
+    let body = match serde_json::to_vec(result) {
+        Ok(body) => body,
+        Err(err) => return println!("unable to serialize callback; {}", err),
+    };
+    let (id, allowed_hosts) = (*id, config.callback_allowed_hosts.clone());
+    task::spawn_blocking(move || send_callback(&url, &id, &body, &allowed_hosts));
+}
+
+/// Keep an upload that failed to decode in the quarantine directory when one is configured.
+fn quarantine_temp_file(config: &Config, image_location: PathBuf, id: &Uuid, format: ImageFormat) {
+    let quarantine = match &config.quarantine {
+        Some(quarantine) => quarantine,
+        None => return remove_temp_file(image_location),
+    };
+    let extension = format.extensions_str().first().unwrap_or(&"bin");
+    match quarantine.store(&image_location, id, extension) {
+        Ok(path) => println!("quarantined temp file, {}", path.to_string_lossy()),
+        Err(err) => {
+            println!("unable to quarantine temp file; {}", err);
+            remove_temp_file(image_location)
+        }
+    }
+}
+
+/// Keep a processed upload in the archive directory when one is configured.
+fn archive_temp_file(config: &Config, image_location: PathBuf, id: &Uuid, format: ImageFormat) {
+    let archive = match &config.archive {
+        Some(archive) => archive,
+        None => return remove_temp_file(image_location),
+    };
+    let extension = format.extensions_str().first().unwrap_or(&"bin");
+    match archive.store(&image_location, id, extension) {
+        Ok(path) => println!("archived temp file, {}", path.to_string_lossy()),
+        Err(err) => {
+            println!("unable to archive temp file; {}", err);
+            remove_temp_file(image_location)
         }
     }
 }
@@ -94,3 +527,209 @@ fn remove_temp_file(image_location: PathBuf) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use image::{codecs::jpeg::JpegEncoder, RgbImage};
+
+    use super::*;
+    use crate::{
+        detection::DetectOptions,
+        image_queue::{Priority, QueueOptions},
+        worker_watchdog::WorkerWatchdog,
+    };
+
+    /// `Config` from the environment, the model is not loaded so any existing file will do.
+    fn test_config() -> Config {
+        std::env::set_var("ULTRA_MODEL_PATH", "Cargo.toml");
+        std::env::set_var("ULTRA_THREADS", "1");
+        Config::new()
+    }
+
+    /// A noisy jpeg, so its entropy coded body is far larger than its header.
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 7 + y * 13) as u8, (x * y) as u8, (x ^ y) as u8])
+        });
+        let mut bytes = Vec::new();
+        JpegEncoder::new(&mut bytes).encode_image(&image).unwrap();
+        bytes
+    }
+
+    #[actix_rt::test]
+    async fn jobs_taken_by_a_panicked_worker_fail() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let mut config = test_config();
+        config.results_dir = dir.clone();
+        let (jobs, stats) = (JobRegistry::new(Duration::from_secs(60)), Stats::new(None));
+        let queue = Arc::new(ImageQueue::new(None));
+        for name in ["taken", "done"] {
+            let upload = dir.join(name);
+            fs::write(&upload, name).unwrap();
+            queue.push(
+                upload,
+                ImageFormat::Png,
+                DetectOptions::default(),
+                QueueOptions {
+                    result_name: Some(name.to_string()),
+                    priority: Priority::Normal,
+                    callback_url: None,
+                    quota_permit: None,
+                },
+            );
+        }
+
+        let (taken, ids) = (Arc::new(TakenJobs::default()), Arc::new(Mutex::new(vec![])));
+        let watchdog = WorkerWatchdog::new(0, Duration::ZERO);
+        watchdog
+            .supervise(
+                || {
+                    let (queue, taken, ids) = (queue.clone(), taken.clone(), ids.clone());
+                    async move {
+                        let (first, second) =
+                            (queue.pop_next().unwrap(), queue.pop_next().unwrap());
+                        ids.lock().unwrap().extend([first.id, second.id]);
+                        let _first = taken.take(&first);
+                        // finished before the panic
+                        drop(taken.take(&second));
+                        panic!("worker bug");
+                    }
+                },
+                || fail_taken_jobs(&taken, &config, &jobs, &stats),
+            )
+            .await;
+
+        let ids = ids.lock().unwrap().clone();
+        let result = fs::read_to_string(dir.join("taken.json")).unwrap();
+        let done_result = dir.join("done.json").exists();
+        let uploads = (dir.join("taken").exists(), dir.join("done").exists());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, r#"{"error":"queue worker crashed"}"#);
+        assert_eq!(
+            jobs.get(&ids[0]).map(|job| job.status),
+            Some(JobStatus::Failed {
+                reason: "queue worker crashed".to_string()
+            })
+        );
+        assert!(jobs.get(&ids[1]).is_none());
+        assert!(!done_result);
+        assert_eq!(uploads, (false, true));
+        assert_eq!(stats.summary(0, 0).failed, 1);
+        assert!(!watchdog.is_alive());
+    }
+
+    #[actix_rt::test]
+    async fn truncated_jpeg_writes_corrupt_image_error_result() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let bytes = jpeg(64, 64);
+        let upload = dir.join("upload.jpg");
+        fs::write(&upload, &bytes[..bytes.len() / 2]).unwrap();
+        let id = Uuid::new_v4();
+        let item = QueueItem {
+            id,
+            image_location: upload,
+            format: ImageFormat::Jpeg,
+            options: DetectOptions::default(),
+            result_name: id.to_string(),
+            added_time: SystemTime::now(),
+            priority: Priority::Normal,
+            callback_url: None,
+            quota_permit: None,
+        };
+
+        let mut config = test_config();
+        config.results_dir = dir.clone();
+        let loaded = finish_decode(&item, &config, start_decode(&item, &config)).await;
+        assert_eq!(loaded.err().as_deref(), Some("corrupt or truncated image"));
+        let result = fs::read_to_string(dir.join(item.result_name + ".json")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, r#"{"error":"corrupt or truncated image"}"#);
+    }
+
+    /// Run `count` jobs through `DecodeAhead`, later jobs decoding faster than earlier ones and job
+    /// 5 failing before its decode. Returns the decoded values in the order they were taken and
+    /// the most decodes running at once.
+    async fn run_jobs(ahead: usize, count: u64) -> (Vec<u64>, usize) {
+        let mut items = 0..count;
+        let mut decoding = DecodeAhead::new(ahead);
+        let (mut decoded, mut most_started) = (vec![], 0);
+        loop {
+            let next = decoding.next(&mut items, |job| {
+                (job != 5).then(|| {
+                    task::spawn_blocking(move || {
+                        std::thread::sleep(Duration::from_millis(count - job));
+                        job * 10
+                    })
+                })
+            });
+            most_started = most_started.max(decoding.started.len() + 1);
+            match next {
+                Some(decode) => decoded.push(decode.await.unwrap()),
+                None => break,
+            }
+        }
+        (decoded, most_started)
+    }
+
+    #[actix_rt::test]
+    async fn decode_ahead_keeps_queue_order() {
+        let expected = vec![0, 10, 20, 30, 40, 60, 70, 80, 90];
+        let (serial, serial_started) = run_jobs(0, 10).await;
+        assert_eq!(serial, expected);
+        assert_eq!(serial_started, 1);
+
+        let (ahead, ahead_started) = run_jobs(3, 10).await;
+        assert_eq!(ahead, serial);
+        assert_eq!(ahead_started, 4);
+    }
+
+    /// Result names in the order the worker takes three normal jobs with `DECODE_AHEAD` at
+    /// `ahead`, a high priority job being queued once it took the first.
+    fn take_with_high_priority_arrival(ahead: usize) -> Vec<String> {
+        let queue = ImageQueue::new(None);
+        let push = |name: &str, priority| {
+            queue.push(
+                PathBuf::from(name),
+                ImageFormat::Png,
+                DetectOptions::default(),
+                QueueOptions {
+                    result_name: Some(name.to_string()),
+                    priority,
+                    callback_url: None,
+                    quota_permit: None,
+                },
+            );
+        };
+        for name in ["normal-1", "normal-2", "normal-3"] {
+            push(name, Priority::Normal);
+        }
+
+        let mut items = iter::from_fn(|| queue.pop_next());
+        let mut decoding = DecodeAhead::new(ahead);
+        let mut taken = vec![];
+        while let Some(item) = decoding.next(&mut items, Some) {
+            if taken.is_empty() {
+                push("high", Priority::High);
+            }
+            taken.push(item.result_name);
+        }
+        taken
+    }
+
+    #[test]
+    fn high_priority_job_arriving_mid_batch_is_next() {
+        assert_eq!(
+            take_with_high_priority_arrival(0),
+            ["normal-1", "high", "normal-2", "normal-3"]
+        );
+        // a job already decoding ahead is finished first
+        assert_eq!(
+            take_with_high_priority_arrival(1),
+            ["normal-1", "normal-2", "high", "normal-3"]
+        );
+    }
+}
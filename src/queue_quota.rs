@@ -0,0 +1,53 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Bounds the queued and processing jobs of each client, the API key or the client IP without
+/// one, so a single tenant can not fill the shared queue and starve the others.
+pub struct QueueQuota {
+    pub max: usize,
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// Held by a queued job until it is done or failed, frees its slot when dropped with the job.
+pub struct QuotaPermit {
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    key: String,
+}
+
+impl QueueQuota {
+    pub fn new(max: usize) -> QueueQuota {
+        QueueQuota {
+            max,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Take a slot of `key`, `None` when it already has `max` jobs in flight.
+    pub fn try_acquire(&self, key: &str) -> Option<QuotaPermit> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(key.to_string()).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(QuotaPermit {
+            in_flight: self.in_flight.clone(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.key) {
+            *count -= 1;
+            // keep the map to the clients with jobs in flight
+            if *count == 0 {
+                in_flight.remove(&self.key);
+            }
+        }
+    }
+}
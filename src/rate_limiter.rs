@@ -0,0 +1,84 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+static MAX_BUCKETS: usize = 10000;
+
+#[derive(Clone, Copy, Serialize)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by API key, or by client IP for requests without one.
+pub struct RateLimiter {
+    default_limit: RateLimit,
+    key_limits: HashMap<String, RateLimit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(default_limit: RateLimit, key_limits: HashMap<String, RateLimit>) -> RateLimiter {
+        RateLimiter {
+            default_limit,
+            key_limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for the client, or return how long it has to wait for the next one.
+    pub fn check(&self, api_key: Option<&str>, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let (bucket_key, limit) = match (api_key, ip) {
+            (Some(api_key), _) => (
+                format!("key:{}", api_key),
+                *self.key_limits.get(api_key).unwrap_or(&self.default_limit),
+            ),
+            (None, Some(ip)) => (format!("ip:{}", ip), self.default_limit),
+            (None, None) => ("unknown".to_string(), self.default_limit),
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&bucket_key) {
+            // drop clients whose bucket has refilled completely, they have been idle
+            let default_limit = self.default_limit;
+            buckets.retain(|_, bucket| {
+                refill(bucket, now, default_limit);
+                bucket.tokens < default_limit.burst
+            });
+        }
+
+        let bucket = buckets.entry(bucket_key).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+        refill(bucket, now, limit);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / limit.requests_per_second))
+        }
+    }
+}
+
+fn refill(bucket: &mut Bucket, now: Instant, limit: RateLimit) {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = f64::min(
+        limit.burst,
+        bucket.tokens + elapsed * limit.requests_per_second,
+    );
+    bucket.updated = now;
+}
@@ -0,0 +1,65 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::Serialize;
+
+/// Extension of result files written with `COMPRESS_RESULTS`.
+pub static COMPRESSED_EXTENSION: &str = ".json.gz";
+
+/// Path of the result `name` in `results_dir`, `{name}.json.gz` when it was stored compressed.
+pub fn result_path(results_dir: &Path, name: &str) -> PathBuf {
+    let compressed = results_dir.join(name.to_string() + COMPRESSED_EXTENSION);
+    match compressed.exists() {
+        true => compressed,
+        false => results_dir.join(name.to_string() + ".json"),
+    }
+}
+
+pub fn is_compressed(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(COMPRESSED_EXTENSION)
+}
+
+/// Read the JSON of a result file, decompressing it when it was stored compressed.
+pub fn read_result(path: &Path) -> io::Result<Vec<u8>> {
+    let result = fs::read(path)?;
+    if !is_compressed(path) {
+        return Ok(result);
+    }
+    let mut json = Vec::new();
+    GzDecoder::new(result.as_slice()).read_to_end(&mut json)?;
+    Ok(json)
+}
+
+/// Serialize `result` to `path`, gzip compressed with `compress`.
+pub fn write_result<T: Serialize>(
+    path: &Path,
+    result: &T,
+    pretty: bool,
+    compress: bool,
+) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    match compress {
+        true => {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            write_json(&mut encoder, result, pretty)?;
+            encoder.finish()?.flush()
+        }
+        false => {
+            let mut writer = writer;
+            write_json(&mut writer, result, pretty)?;
+            writer.flush()
+        }
+    }
+}
+
+fn write_json<W: Write, T: Serialize>(writer: W, result: &T, pretty: bool) -> io::Result<()> {
+    match pretty {
+        true => serde_json::to_writer_pretty(writer, result)?,
+        false => serde_json::to_writer(writer, result)?,
+    };
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use std::{fs::File, io, path::Path};
+
+use sha2::{Digest, Sha256};
+
+static MAX_NAME_LENGTH: usize = 128;
+
+/// Check a client supplied result name can be used as a file name in the results directory.
+/// Only ascii letters, digits, `-`, `_` and `.` are allowed, and no leading `.`, so names can
+/// not traverse out of the directory or be hidden files.
+pub fn check_name(name: &str) -> Result<&str, &'static str> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    match valid {
+        true => Ok(name),
+        false => Err("name must be 1-128 of a-z, A-Z, 0-9, -, _ and . and not start with ."),
+    }
+}
+
+/// Hex encoded sha256 of the file at `path`.
+pub fn content_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::statsd::StatsdClient;
+
+static INFERENCE_SAMPLES: usize = 1000;
+
+pub struct Stats {
+    pub start_time: Instant,
+    counters: Mutex<Counters>,
+    /// Also pushes every recorded event, `None` without `STATSD_HOST`.
+    pub statsd: Option<StatsdClient>,
+}
+
+struct Counters {
+    enqueued: u64,
+    processed: u64,
+    failed: u64,
+    inference_times: VecDeque<Duration>,
+}
+
+#[derive(Serialize)]
+pub struct StatsSummary {
+    pub enqueued: u64,
+    pub processed: u64,
+    pub failed: u64,
+    pub avg_inference_ms: f64,
+    pub p95_inference_ms: f64,
+    pub queue_depth: usize,
+    /// Inferences currently running or preparing their input.
+    pub inference_in_flight: usize,
+    pub uptime_secs: u64,
+}
+
+/// Autoscaling signal of `/scale`, for an external autoscaler polling the server.
+#[derive(Serialize)]
+pub struct ScaleSignal {
+    pub queue_depth: usize,
+    pub avg_processing_ms: f64,
+    pub target_latency_ms: u128,
+    /// Workers needed to work off the queue within the target latency, at least 1.
+    pub recommended_workers: u64,
+}
+
+impl Stats {
+    pub fn new(statsd: Option<StatsdClient>) -> Stats {
+        Stats {
+            start_time: Instant::now(),
+            counters: Mutex::new(Counters {
+                enqueued: 0,
+                processed: 0,
+                failed: 0,
+                inference_times: VecDeque::with_capacity(INFERENCE_SAMPLES),
+            }),
+            statsd,
+        }
+    }
+
+    pub fn record_enqueued(&self) {
+        self.counters.lock().unwrap().enqueued += 1;
+        if let Some(statsd) = &self.statsd {
+            statsd.count("enqueued");
+        }
+    }
+
+    pub fn record_processed(&self) {
+        self.counters.lock().unwrap().processed += 1;
+        if let Some(statsd) = &self.statsd {
+            statsd.count("processed");
+        }
+    }
+
+    pub fn record_failed(&self) {
+        self.counters.lock().unwrap().failed += 1;
+        if let Some(statsd) = &self.statsd {
+            statsd.count("failed");
+        }
+    }
+
+    /// Keep the last `INFERENCE_SAMPLES` inference durations, used for the average and p95.
+    pub fn record_inference_time(&self, duration: Duration) {
+        let mut counters = self.counters.lock().unwrap();
+        if counters.inference_times.len() >= INFERENCE_SAMPLES {
+            counters.inference_times.pop_front();
+        }
+        counters.inference_times.push_back(duration);
+        if let Some(statsd) = &self.statsd {
+            statsd.timing("inference_time", duration);
+        }
+    }
+
+    /// Workers needed to process `queue_depth` jobs at the average inference time within
+    /// `target_latency`, each worker processing one job at a time.
+    pub fn scale(&self, queue_depth: usize, target_latency: Duration) -> ScaleSignal {
+        let avg_processing_ms = self.summary(queue_depth, 0).avg_inference_ms;
+        let target_latency_ms = target_latency.as_millis();
+        let backlog_ms = queue_depth as f64 * avg_processing_ms;
+        let recommended_workers = (backlog_ms / target_latency_ms.max(1) as f64).ceil() as u64;
+        ScaleSignal {
+            queue_depth,
+            avg_processing_ms,
+            target_latency_ms,
+            recommended_workers: recommended_workers.max(1),
+        }
+    }
+
+    pub fn summary(&self, queue_depth: usize, inference_in_flight: usize) -> StatsSummary {
+        let counters = self.counters.lock().unwrap();
+        let mut inference_ms: Vec<f64> = counters
+            .inference_times
+            .iter()
+            .map(|duration| duration.as_secs_f64() * 1000.0)
+            .collect();
+        inference_ms.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let avg_inference_ms = match inference_ms.len() {
+            0 => 0.0,
+            n => inference_ms.iter().sum::<f64>() / n as f64,
+        };
+        let p95_inference_ms = match inference_ms.len() {
+            0 => 0.0,
+            n => inference_ms[((n as f64 * 0.95).ceil() as usize).min(n) - 1],
+        };
+
+        StatsSummary {
+            enqueued: counters.enqueued,
+            processed: counters.processed,
+            failed: counters.failed,
+            avg_inference_ms,
+            p95_inference_ms,
+            queue_depth,
+            inference_in_flight,
+            uptime_secs: self.start_time.elapsed().as_secs(),
+        }
+    }
+}
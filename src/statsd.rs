@@ -0,0 +1,47 @@
+use std::{io, net::UdpSocket, time::Duration};
+
+/// Pushes metrics to a StatsD or DogStatsD agent over UDP, for setups without a scraper polling
+/// `/stats`. Sending is best effort, a metric the agent does not receive is lost.
+pub struct StatsdClient {
+    socket: UdpSocket,
+    prefix: String,
+    /// DogStatsD tags appended to every metric as `|#{tags}`, plain StatsD takes none.
+    tags: Option<String>,
+}
+
+impl StatsdClient {
+    /// Resolve the agent at `address`, `host:port`, once and send every metric there.
+    pub fn connect(address: &str, prefix: &str, tags: Option<String>) -> io::Result<StatsdClient> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdClient {
+            socket,
+            prefix: prefix.to_string(),
+            tags,
+        })
+    }
+
+    pub fn count(&self, name: &str) {
+        self.send(name, "1", "c");
+    }
+
+    pub fn timing(&self, name: &str, duration: Duration) {
+        let millis = format!("{:.3}", duration.as_secs_f64() * 1000.0);
+        self.send(name, &millis, "ms");
+    }
+
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.send(name, &value.to_string(), "g");
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str) {
+        let mut metric = format!("{}.{}:{}|{}", self.prefix, name, value, kind);
+        if let Some(tags) = &self.tags {
+            metric.push_str("|#");
+            metric.push_str(tags);
+        }
+        // a missing agent shows up as a refused send, metrics must never fail a job
+        let _ = self.socket.send(metric.as_bytes());
+    }
+}
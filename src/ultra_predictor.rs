@@ -1,48 +1,242 @@
-use std::{path::Path, sync::Mutex, time::Instant};
+use std::{
+    ops::AddAssign,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use image::RgbImage;
-use ndarray::{s, Array4, CowArray, IxDyn};
+use image::{imageops::FilterType, DynamicImage, RgbImage};
+use ndarray::{s, Array4, ArrayViewD, CowArray, IxDyn};
 use ort::{
     tensor::OrtOwnedTensor, Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel,
     OrtError, Session, SessionBuilder, Value,
 };
+use serde::Serialize;
+
+use crate::{
+    anchors::Anchors,
+    config::{AcceptMode, ChannelOrder, Config, ExecutionProviderKind, InputLayout, NmsMode},
+    detection::ModelMetadata,
+    error::Error,
+    inference_limit::InferenceLimit,
+    model_source::ModelSource,
+    quality::QualityCheck,
+};
 
-type Bbox = [f32; 4]; //[x_top_left, y_top_left, x_bottom_right, y_bottom_right]
-type BboxPixels = [u32; 4]; //[x_top_left, y_top_left, x_bottom_right, y_bottom_right]
+pub type Bbox = [f32; 4]; //[x_top_left, y_top_left, x_bottom_right, y_bottom_right]
+pub type BboxPixels = [u32; 4]; //[x_top_left, y_top_left, x_bottom_right, y_bottom_right]
+/// Boxes found by one detection with their confidences.
+pub type Detections = Vec<(BboxPixels, f32)>;
+/// Box of a candidate relative to the model input, its most confident face class and confidence.
+pub type Candidate = (Bbox, usize, f32);
 
 pub struct UltraPredictor {
     pub name: String,
+    /// Id and version of the loaded model, added to every result.
+    pub model: ModelMetadata,
+    /// `INSTANCE_ID` added to every result.
+    pub instance_id: String,
     pub session: Mutex<Session>,
+    /// Candidates at or below this confidence are dropped before non-maximum-suppression.
+    pub confidence_threshold: f32,
+    /// Boxes surviving non-maximum-suppression below this confidence are not reported.
+    pub report_confidence: f32,
+    /// Only suppress overlapping boxes of the same class, for models with several face classes.
+    pub nms_per_class: bool,
+    /// Prior boxes for models outputting box regressions instead of corners.
+    pub anchors: Option<Anchors>,
+    /// Cheaply shrink huge images before the quality resize to the model input.
+    pub prescale: bool,
+    /// `(min, max)` width:height of reported boxes, other shapes are unlikely to be faces.
+    pub aspect_ratio: (f32, f32),
+    pub inference_limit: InferenceLimit,
+    pub nms_mode: NmsMode,
+    pub max_nms_candidates: usize,
+    /// Layout of the input tensor, configured or detected from the model.
+    pub input_layout: InputLayout,
+    pub channel_order: ChannelOrder,
+    /// Results without a face of at least this confidence are not accepted.
+    pub min_accept_confidence: Option<f32>,
+    pub accept_mode: AcceptMode,
+    /// Growth of boxes touching the image border, see `EDGE_PADDING`.
+    pub edge_padding: Option<f32>,
+    /// Brightness and contrast pre-check of the image detection runs on.
+    pub quality_check: Option<QualityCheck>,
+    /// Largest side of the thumbnail embedded in every result, `None` embeds none.
+    pub thumbnail_max_size: Option<u32>,
+    /// Faces per megapixel from which a result is crowded, `None` measures no density.
+    pub crowd_density_threshold: Option<f32>,
+    /// Consecutive inference failures after which the session is rebuilt.
+    pub inference_failure_limit: usize,
+    session_loader: SessionLoader,
+    consecutive_failures: AtomicUsize,
+    /// The session was rebuilt and has not run successfully since.
+    rebuilt: AtomicBool,
+    healthy: AtomicBool,
+}
+
+/// Everything needed to load the session again, see `UltraPredictor::recover`.
+struct SessionLoader {
+    environment: Arc<Environment>,
+    model_source: ModelSource,
+    intra_threads: i16,
+    inter_threads: Option<i16>,
+}
+
+impl SessionLoader {
+    fn load(&self) -> Result<Session, OrtError> {
+        let mut session_builder = SessionBuilder::new(&self.environment)?
+            .with_optimization_level(GraphOptimizationLevel::Disable)?
+            .with_intra_threads(self.intra_threads)?;
+        // inter-op threads are only used when the graph is executed in parallel
+        if let Some(inter_threads) = self.inter_threads {
+            session_builder = session_builder
+                .with_parallel_execution(true)?
+                .with_inter_threads(inter_threads)?;
+        }
+        match &self.model_source {
+            ModelSource::File(model_filepath) => {
+                session_builder.with_model_from_file(model_filepath)
+            }
+            ModelSource::Memory(model_bytes) => session_builder.with_model_from_memory(model_bytes),
+        }
+    }
 }
 
 pub struct UltraOutput {
     pub bboxes_with_confidences: Vec<(BboxPixels, f32)>,
+    pub timings: InferenceTimings,
+}
+
+/// A model output before thresholding and non-maximum-suppression.
+#[derive(Serialize)]
+pub struct RawCandidate {
+    pub bbox: BboxPixels,
+    /// Most confident face class, 1 for single class models.
+    pub class: usize,
+    pub confidence: f32,
+}
+
+/// Shape of a model input or output, `None` for dynamic dimensions.
+#[derive(Serialize)]
+pub struct TensorInfo {
+    pub name: String,
+    pub element_type: String,
+    pub dimensions: Vec<Option<u32>>,
+}
+
+/// Inputs and outputs of the loaded model, for checking an export matches what `post_process`
+/// expects: confidences `[1, N, 2]` and boxes `[1, N, 4]`.
+#[derive(Serialize)]
+pub struct ModelInfo {
+    pub inputs: Vec<TensorInfo>,
+    pub outputs: Vec<TensorInfo>,
+    /// Number of candidate boxes `N`, from the second dimension of the first output.
+    pub candidate_boxes: Option<u32>,
+    pub input_layout: &'static str,
+}
+
+/// Time spent in each step of `UltraPredictor::run`.
+#[derive(Clone, Copy, Default)]
+pub struct InferenceTimings {
+    pub resize: Duration,
+    /// Building the normalized input tensor.
+    pub tensor: Duration,
+    /// Running the session, including waiting for the session lock.
+    pub inference: Duration,
+    /// Non-maximum-suppression and mapping boxes back to image pixels.
+    pub post_process: Duration,
+}
+
+impl AddAssign for InferenceTimings {
+    fn add_assign(&mut self, other: InferenceTimings) {
+        self.resize += other.resize;
+        self.tensor += other.tensor;
+        self.inference += other.inference;
+        self.post_process += other.post_process;
+    }
 }
 
-static CONFIDENCE_THRESHOLD: f32 = 0.5;
 static MAX_IOU: f32 = 0.5;
+static PRESCALE_FACTOR: usize = 2;
 static ULTRA_PREDICTOR_NAME: &str = "UltraPredictor";
 pub static ULTRA_INPUT_WIDTH: usize = 640;
 pub static ULTRA_INPUT_HEIGHT: usize = 480;
 static ULTRA_RATIO: f32 = ULTRA_INPUT_WIDTH as f32 / ULTRA_INPUT_HEIGHT as f32;
-static EPS: f32 = 1.0e-7;
 /// Positive additive constant to avoid divide-by-zero.
+static EPS: f32 = 1.0e-7;
+/// Candidates from which non-maximum-suppression only compares boxes in the same `NmsGrid` cells.
+static NMS_GRID_MIN_CANDIDATES: usize = 256;
+/// Cells per side of the `NmsGrid`.
+static NMS_GRID_SIZE: usize = 16;
 
 impl UltraPredictor {
-    pub fn new(model_filepath: &Path, num_threads: &i16) -> Result<UltraPredictor, OrtError> {
+    pub fn new(config: &Config) -> Result<UltraPredictor, Error> {
         let start = Instant::now();
 
+        // verbose logging also shows which nodes an accelerated provider left to the CPU
         let environment = Environment::builder()
             .with_name(ULTRA_PREDICTOR_NAME.to_string())
-            .with_execution_providers([ExecutionProvider::CPU(Default::default())])
+            .with_execution_providers(execution_providers(config.execution_provider))
             .with_log_level(LoggingLevel::Verbose)
             .build()?
             .into_arc();
 
-        let session = SessionBuilder::new(&environment)?
-            .with_optimization_level(GraphOptimizationLevel::Disable)?
-            .with_intra_threads(*num_threads)?
-            .with_model_from_file(&model_filepath)?;
+        let session_loader = SessionLoader {
+            environment,
+            model_source: config.model_source.clone(),
+            intra_threads: config.ultra_threads,
+            inter_threads: config.ultra_inter_threads,
+        };
+        // onnxruntime parses onnx files straight from the path, there is no memory mapped loading
+        // to gain from for `.onnx` models, so only the time spent loading is logged
+        let load_start = Instant::now();
+        let session = session_loader.load()?;
+        println!(
+            "{} loaded model from {} in {:?}",
+            ULTRA_PREDICTOR_NAME,
+            config.model_source,
+            load_start.elapsed()
+        );
+
+        let anchors = config
+            .anchor_decoding
+            .map(|(center_variance, size_variance)| {
+                Anchors::new(
+                    ULTRA_INPUT_WIDTH,
+                    ULTRA_INPUT_HEIGHT,
+                    center_variance,
+                    size_variance,
+                )
+            });
+        if let Some(anchors) = &anchors {
+            let candidate_boxes = session
+                .outputs
+                .get(1)
+                .and_then(|output| output.dimensions.get(1).copied().flatten());
+            if candidate_boxes.is_some_and(|boxes| boxes as usize != anchors.len()) {
+                println!(
+                    "model outputs {:?} boxes but {} prior boxes were generated",
+                    candidate_boxes,
+                    anchors.len()
+                );
+            }
+        }
+
+        // a channel dimension of 3 at the end and not after the batch is a channels-last input
+        let input_layout = config.input_layout.unwrap_or_else(|| {
+            match session
+                .inputs
+                .first()
+                .map(|input| input.dimensions.as_slice())
+            {
+                Some([_, channels, _, Some(3)]) if *channels != Some(3) => InputLayout::Nhwc,
+                _ => InputLayout::Nchw,
+            }
+        });
+        println!("model input layout is {}", input_layout.name());
 
         println!(
             "{} startup took {:?}",
@@ -51,82 +245,437 @@ impl UltraPredictor {
         );
         Ok(UltraPredictor {
             name: ULTRA_PREDICTOR_NAME.to_string(),
+            model: ModelMetadata {
+                id: config.model_id.clone(),
+                version: config.model_version.clone(),
+            },
+            instance_id: config.instance_id.clone(),
             session: session.into(),
+            confidence_threshold: config.confidence_threshold,
+            report_confidence: config.report_confidence,
+            nms_per_class: config.nms_per_class,
+            anchors,
+            prescale: config.prescale,
+            aspect_ratio: config.box_aspect_ratio,
+            inference_limit: InferenceLimit::new(config.max_concurrent_inference),
+            nms_mode: config.nms_mode,
+            max_nms_candidates: config.max_nms_candidates,
+            input_layout,
+            channel_order: config.channel_order,
+            min_accept_confidence: config.min_accept_confidence,
+            accept_mode: config.accept_mode,
+            edge_padding: config.edge_padding,
+            quality_check: config.quality_check,
+            thumbnail_max_size: config.thumbnail_max_size,
+            crowd_density_threshold: config.crowd_density_threshold,
+            inference_failure_limit: config.inference_failure_limit,
+            session_loader,
+            consecutive_failures: AtomicUsize::new(0),
+            rebuilt: AtomicBool::new(false),
+            healthy: AtomicBool::new(true),
         })
     }
 
-    pub fn run(&self, image: &RgbImage) -> Result<UltraOutput, OrtError> {
+    /// Detect faces in `image`, the returned boxes are in pixel coordinates of `image`.
+    pub fn run(
+        &self,
+        image: &DynamicImage,
+        resize_filter: FilterType,
+    ) -> Result<UltraOutput, Error> {
+        let (mut detections, timings) =
+            self.run_at_thresholds(image, resize_filter, &[self.confidence_threshold])?;
+        Ok(UltraOutput {
+            bboxes_with_confidences: detections.remove(0),
+            timings,
+        })
+    }
+
+    /// Like `run`, but post processes the output of a single inference once per confidence
+    /// threshold, returning the boxes in the order of `thresholds`.
+    pub fn run_at_thresholds(
+        &self,
+        image: &DynamicImage,
+        resize_filter: FilterType,
+        thresholds: &[f32],
+    ) -> Result<(Vec<Detections>, InferenceTimings), Error> {
+        let _permit = self.inference_limit.acquire();
         let start = Instant::now();
 
-        let image_tensor = self.get_image_tensor(&image);
-        let image_input = self.get_image_input(&image_tensor)?;
-        let raw_outputs = self.session.lock().unwrap().run(image_input)?;
-        let bboxes_with_confidences = self.post_process(&raw_outputs)?;
-        let ultra_output =
-            map_bboxes_to_bbox_with_pixels(image.width(), image.height(), bboxes_with_confidences);
+        let resized_image = resize_for_model(image, resize_filter, self.prescale);
+        let resized = Instant::now();
+        let image_tensor = get_image_tensor(&resized_image, self.input_layout, self.channel_order);
+        let tensor_built = Instant::now();
+        let raw_outputs = self.infer(&image_tensor)?;
+        let inferred = Instant::now();
+        let candidates = self.get_candidates(&raw_outputs)?;
+        let detections = thresholds
+            .iter()
+            .map(|threshold| {
+                let bboxes_with_confidences = post_process(
+                    &candidates,
+                    *threshold,
+                    self.report_confidence,
+                    self.nms_per_class,
+                    self.nms_mode,
+                    self.max_nms_candidates,
+                );
+                let mut detections = map_bboxes_to_bbox_with_pixels(
+                    image.width(),
+                    image.height(),
+                    bboxes_with_confidences,
+                );
+                detections.retain(|(bbox, _)| has_aspect_ratio(bbox, self.aspect_ratio));
+                detections
+            })
+            .collect();
 
+        let timings = InferenceTimings {
+            resize: resized - start,
+            tensor: tensor_built - resized,
+            inference: inferred - tensor_built,
+            post_process: inferred.elapsed(),
+        };
         println!(
             "{} preprocessing and inference took {:?}",
             ULTRA_PREDICTOR_NAME,
             start.elapsed()
         );
-        Ok(UltraOutput {
-            bboxes_with_confidences: ultra_output,
-        })
+        Ok((detections, timings))
     }
 
-    fn get_image_tensor(&self, image: &RgbImage) -> CowArray<f32, IxDyn> {
-        let image_tensor = CowArray::from(Array4::from_shape_fn(
-            (1, 3, ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH),
-            |(_, c, y, x)| {
-                let mean = [0.485, 0.456, 0.406][c];
-                let std = [0.229, 0.224, 0.225][c];
-                (image[(x as _, y as _)][c] as f32 / 255.0 - mean) / std
-            },
-        ))
-        .into_dyn();
+    pub fn info(&self) -> ModelInfo {
+        let session = self.session.lock().unwrap();
+        let inputs = session
+            .inputs
+            .iter()
+            .map(|input| TensorInfo {
+                name: input.name.clone(),
+                element_type: format!("{:?}", input.input_type),
+                dimensions: input.dimensions.clone(),
+            })
+            .collect();
+        let outputs: Vec<TensorInfo> = session
+            .outputs
+            .iter()
+            .map(|output| TensorInfo {
+                name: output.name.clone(),
+                element_type: format!("{:?}", output.output_type),
+                dimensions: output.dimensions.clone(),
+            })
+            .collect();
+        let candidate_boxes = outputs
+            .first()
+            .and_then(|output| output.dimensions.get(1).copied().flatten());
+
+        ModelInfo {
+            inputs,
+            outputs,
+            candidate_boxes,
+            input_layout: self.input_layout.name(),
+        }
+    }
 
-        return image_tensor;
+    /// Fast path returning whether any candidate passes the confidence thresholds and the box
+    /// aspect ratio filter. Stops at the first such candidate and skips non-maximum-suppression,
+    /// so no boxes are produced.
+    pub fn has_face(&self, image: &DynamicImage, resize_filter: FilterType) -> Result<bool, Error> {
+        let _permit = self.inference_limit.acquire();
+        let resized_image = resize_for_model(image, resize_filter, self.prescale);
+        let image_tensor = get_image_tensor(&resized_image, self.input_layout, self.channel_order);
+        let raw_outputs = self.infer(&image_tensor)?;
+
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let candidates = self.get_candidates(&raw_outputs)?;
+        let has_face = candidates.into_iter().any(|(bbox, _, confidence)| {
+            confidence > self.confidence_threshold
+                && confidence >= self.report_confidence
+                && has_aspect_ratio(
+                    &get_bbox_pixel_locations(width, height, bbox),
+                    self.aspect_ratio,
+                )
+        });
+
+        Ok(has_face)
     }
 
-    fn get_image_input<'a>(
+    /// Run the session on the input tensor, locking it once for allocating the input value and
+    /// the run. The outputs are owned and outlive the lock.
+    fn infer<'a>(
         &self,
         image_tensor: &'a CowArray<'a, f32, IxDyn>,
-    ) -> Result<Vec<Value<'a>>, OrtError> {
-        let input_value =
-            Value::from_array(self.session.lock().unwrap().allocator(), &image_tensor)?;
-        let input = vec![input_value];
-
-        return Ok(input);
+    ) -> Result<Vec<Value<'static>>, OrtError> {
+        let mut session = self.session.lock().unwrap();
+        let outputs = Value::from_array(session.allocator(), image_tensor)
+            .and_then(|input| session.run(vec![input]));
+        match &outputs {
+            Ok(_) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                self.rebuilt.store(false, Ordering::Relaxed);
+                self.healthy.store(true, Ordering::Relaxed);
+            }
+            Err(_) => {
+                let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.inference_failure_limit {
+                    self.recover(&mut session);
+                }
+            }
+        }
+        outputs
     }
 
-    fn post_process(&self, raw_outputs: &Vec<Value>) -> Result<Vec<(Bbox, f32)>, OrtError> {
-        let output_0: OrtOwnedTensor<f32, _> = raw_outputs[0].try_extract()?;
-        let confidences_view = output_0.view();
-        let confidences = confidences_view.slice(s![0, .., 1]);
+    /// Circuit breaker for a session stuck failing, i.e. after running out of GPU memory. The
+    /// session is rebuilt once, when that fails or the new session keeps failing as well the
+    /// predictor is unhealthy until an inference succeeds again.
+    fn recover(&self, session: &mut Session) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if self.rebuilt.swap(true, Ordering::Relaxed) {
+            println!("inference keeps failing after rebuilding the session, marking unhealthy");
+            self.healthy.store(false, Ordering::Relaxed);
+            return;
+        }
 
-        let output_1: OrtOwnedTensor<f32, _> = raw_outputs[1].try_extract()?;
-        let bbox_view = output_1.view();
-        let bbox_arr = bbox_view.to_slice().unwrap().to_vec();
-        let bboxes: Vec<Bbox> = bbox_arr.chunks(4).map(|x| x.try_into().unwrap()).collect();
+        println!(
+            "{} consecutive inference failures, rebuilding the session",
+            self.inference_failure_limit
+        );
+        match self.session_loader.load() {
+            Ok(new_session) => *session = new_session,
+            Err(err) => {
+                println!("unable to rebuild the session, marking unhealthy; {}", err);
+                self.healthy.store(false, Ordering::Relaxed);
+            }
+        }
+    }
 
-        let mut bboxes_with_confidences: Vec<_> = bboxes
-            .iter()
-            .zip(confidences.iter())
-            .filter_map(|(bbox, confidence)| match confidence {
-                x if *x > CONFIDENCE_THRESHOLD => Some((bbox, confidence)),
-                _ => None,
+    /// Every candidate of an inference on `image` before the confidence threshold and
+    /// non-maximum-suppression, in pixel coordinates of `image`, to debug why a face is missed.
+    pub fn raw_candidates(
+        &self,
+        image: &DynamicImage,
+        resize_filter: FilterType,
+    ) -> Result<Vec<RawCandidate>, Error> {
+        let _permit = self.inference_limit.acquire();
+        let resized_image = resize_for_model(image, resize_filter, self.prescale);
+        let image_tensor = get_image_tensor(&resized_image, self.input_layout, self.channel_order);
+        let raw_outputs = self.infer(&image_tensor)?;
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let candidates = self
+            .get_candidates(&raw_outputs)?
+            .into_iter()
+            .map(|(bbox, class, confidence)| RawCandidate {
+                bbox: get_bbox_pixel_locations(width, height, bbox),
+                class,
+                confidence,
             })
             .collect();
+        Ok(candidates)
+    }
+
+    /// False once inference kept failing after rebuilding the session, see `recover`.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn get_candidates(&self, raw_outputs: &[Value]) -> Result<Vec<Candidate>, Error> {
+        let (output_0, output_1) = match raw_outputs {
+            [output_0, output_1, ..] => (output_0, output_1),
+            _ => return Err(Error::ModelOutput("expected confidences and boxes")),
+        };
+        let output_0: OrtOwnedTensor<f32, _> = output_0.try_extract()?;
+        let output_1: OrtOwnedTensor<f32, _> = output_1.try_extract()?;
+        decode_candidates(output_0.view(), output_1.view(), self.anchors.as_ref())
+    }
+}
+
+/// Normalize `image`, already resized to the model input, into the `[1, 3, height, width]` input
+/// tensor, or `[1, height, width, 3]` for `InputLayout::Nhwc`. With `ChannelOrder::Bgr` channel 0
+/// is blue, each color keeps its own mean and standard deviation.
+pub fn get_image_tensor(
+    image: &RgbImage,
+    layout: InputLayout,
+    channel_order: ChannelOrder,
+) -> CowArray<'_, f32, IxDyn> {
+    let normalize = |c: usize, y: usize, x: usize| {
+        let color = match channel_order {
+            ChannelOrder::Rgb => c,
+            ChannelOrder::Bgr => 2 - c,
+        };
+        let mean = [0.485, 0.456, 0.406][color];
+        let std = [0.229, 0.224, 0.225][color];
+        (image[(x as _, y as _)][color] as f32 / 255.0 - mean) / std
+    };
+    let image_tensor = match layout {
+        InputLayout::Nchw => Array4::from_shape_fn(
+            (1, 3, ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH),
+            |(_, c, y, x)| normalize(c, y, x),
+        ),
+        InputLayout::Nhwc => Array4::from_shape_fn(
+            (1, ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH, 3),
+            |(_, y, x, c)| normalize(c, y, x),
+        ),
+    };
+    let image_tensor = CowArray::from(image_tensor).into_dyn();
+
+    return image_tensor;
+}
+
+/// Boxes of all candidates with their most confident face class and its confidence, from the
+/// `[1, boxes, classes]` confidences and `[1, boxes, 4]` boxes the model outputs.
+pub fn decode_candidates(
+    confidences: ArrayViewD<f32>,
+    bboxes: ArrayViewD<f32>,
+    anchors: Option<&Anchors>,
+) -> Result<Vec<Candidate>, Error> {
+    check_confidences(&confidences)?;
+    // column 0 is the background, each candidate is its most confident face class
+    let classes_with_confidences: Vec<(usize, f32)> = confidences
+        .slice(s![0, .., 1..])
+        .outer_iter()
+        .map(|scores| {
+            scores
+                .iter()
+                .enumerate()
+                .fold((0, f32::MIN), |best, (class, confidence)| {
+                    match *confidence > best.1 {
+                        true => (class, *confidence),
+                        false => best,
+                    }
+                })
+        })
+        .collect();
+
+    let bbox_arr = bboxes
+        .to_slice()
+        .ok_or(Error::ModelOutput("boxes are not contiguous"))?;
+    let mut bboxes = bbox_arr
+        .chunks(4)
+        .map(|x| Bbox::try_from(x).map_err(|_| Error::ModelOutput("boxes must have 4 coordinates")))
+        .collect::<Result<Vec<Bbox>, Error>>()?;
+    if let Some(anchors) = anchors {
+        bboxes = anchors.decode(&bboxes);
+    }
 
-        bboxes_with_confidences.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap());
-        let selected_bboxes_with_confidences =
-            non_maximum_suppression(bboxes_with_confidences, MAX_IOU).to_vec();
+    Ok(bboxes
+        .into_iter()
+        .zip(classes_with_confidences)
+        .map(|(bbox, (class, confidence))| (bbox, class, confidence))
+        .collect())
+}
 
-        return Ok(selected_bboxes_with_confidences);
+/// Check the confidences have a background and at least one face class per box.
+fn check_confidences(confidences: &ArrayViewD<f32>) -> Result<(), Error> {
+    match confidences.shape() {
+        &[batch, _, classes] if batch > 0 && classes >= 2 => Ok(()),
+        _ => Err(Error::ModelOutput(
+            "confidences must be [1, boxes, classes]",
+        )),
     }
 }
 
+/// Keep the candidates above `confidence_threshold`, suppress overlapping ones and drop what is
+/// below `report_confidence`. Boxes stay relative to the model input. Only the
+/// `max_candidates` most confident candidates are suppressed, as non-maximum-suppression is
+/// quadratic in their number.
+pub fn post_process(
+    candidates: &[Candidate],
+    confidence_threshold: f32,
+    report_confidence: f32,
+    nms_per_class: bool,
+    nms_mode: NmsMode,
+    max_candidates: usize,
+) -> Vec<(Bbox, f32)> {
+    let mut bboxes_with_confidences: Vec<_> = candidates
+        .iter()
+        .filter_map(|(bbox, class, confidence)| match confidence {
+            x if *x > confidence_threshold => {
+                Some((bbox, confidence, nms_per_class.then_some(*class)))
+            }
+            _ => None,
+        })
+        .collect();
+
+    bboxes_with_confidences.sort_by(|a, b| a.1.total_cmp(b.1));
+    // sorted ascending, the most confident candidates are at the back
+    let excess = bboxes_with_confidences.len().saturating_sub(max_candidates);
+    bboxes_with_confidences.drain(..excess);
+    let mut selected_bboxes_with_confidences =
+        non_maximum_suppression(bboxes_with_confidences, MAX_IOU, nms_mode);
+    selected_bboxes_with_confidences.retain(|(_, confidence)| *confidence >= report_confidence);
+
+    return selected_bboxes_with_confidences;
+}
+
+/// Providers in order of preference, ending with the CPU so onnxruntime can fall back to it when
+/// the requested provider fails to initialize or does not support an operator.
+fn execution_providers(kind: ExecutionProviderKind) -> Vec<ExecutionProvider> {
+    let cpu = ExecutionProvider::CPU(Default::default());
+    let provider = match kind {
+        ExecutionProviderKind::Cpu => return vec![cpu],
+        ExecutionProviderKind::CoreML => ExecutionProvider::CoreML(Default::default()),
+        ExecutionProviderKind::ROCm => ExecutionProvider::ROCm(Default::default()),
+        ExecutionProviderKind::DirectML => ExecutionProvider::DirectML(Default::default()),
+    };
+    if !provider.is_available() {
+        println!(
+            "{} execution provider is not available, falling back to CPU",
+            provider.as_str()
+        );
+        return vec![cpu];
+    }
+    vec![provider, cpu]
+}
+
+/// Resize and center crop `image` to the model input. With `prescale` images more than
+/// `PRESCALE_FACTOR` times the input size are first shrunk with a cheap box filter to that size,
+/// keeping the aspect ratio, so the final quality resize works on far fewer pixels.
+pub fn resize_for_model(
+    image: &DynamicImage,
+    resize_filter: FilterType,
+    prescale: bool,
+) -> RgbImage {
+    let scale = f32::max(
+        (PRESCALE_FACTOR * ULTRA_INPUT_WIDTH) as f32 / image.width() as f32,
+        (PRESCALE_FACTOR * ULTRA_INPUT_HEIGHT) as f32 / image.height() as f32,
+    );
+    let prescaled;
+    let image = match prescale && scale < 1.0 {
+        true => {
+            prescaled = image.thumbnail_exact(
+                (image.width() as f32 * scale).ceil() as u32,
+                (image.height() as f32 * scale).ceil() as u32,
+            );
+            &prescaled
+        }
+        false => image,
+    };
+
+    image
+        .resize_to_fill(
+            ULTRA_INPUT_WIDTH as u32,
+            ULTRA_INPUT_HEIGHT as u32,
+            resize_filter,
+        )
+        .to_rgb8()
+}
+
+/// Merge boxes from several inference runs on the same image with non-maximum-suppression.
+pub fn merge_detections(detections: Vec<(BboxPixels, f32)>) -> Vec<(BboxPixels, f32)> {
+    let mut bboxes_with_confidences: Vec<(Bbox, f32)> = detections
+        .into_iter()
+        .map(|(bbox, confidence)| (bbox.map(|value| value as f32), confidence))
+        .collect();
+    bboxes_with_confidences.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let candidates = bboxes_with_confidences
+        .iter()
+        .map(|(bbox, confidence)| (bbox, confidence, None))
+        .collect();
+    non_maximum_suppression(candidates, MAX_IOU, NmsMode::Hard)
+        .into_iter()
+        .map(|(bbox, confidence)| (bbox.map(|value| value as u32), confidence))
+        .collect()
+}
+
 /// Run non-maximum-suppression on candidate bounding boxes.
 ///
 /// The pairs of bounding boxes with confidences have to be sorted in **ascending** order of
@@ -137,32 +686,158 @@ impl UltraPredictor {
 /// candidates which do not have a IoU scores above `max_iou` with already chosen bounding boxes.
 /// This iterates over all bounding boxes in `sorted_bboxes_with_confidences`. Any candidates with
 /// scores generally too low to be considered should be filtered out before.
+///
+/// Candidates may carry a class id, a box then only suppresses boxes of the same class. Boxes
+/// without a class (`None`) are suppressed class-agnostic.
+///
+/// With `NmsMode::WeightedFusion` the suppressed boxes are not discarded but averaged into the box
+/// that suppressed them, weighted by confidence. The same boxes are selected and keep their
+/// confidence, only their coordinates change.
+///
+/// From `NMS_GRID_MIN_CANDIDATES` candidates on, a candidate is only compared to the selected
+/// boxes sharing a cell of an `NmsGrid` with it, with the same result.
 fn non_maximum_suppression(
-    mut sorted_bboxes_with_confidences: Vec<(&Bbox, &f32)>,
+    sorted_bboxes_with_confidences: Vec<(&Bbox, &f32, Option<usize>)>,
     max_iou: f32,
+    mode: NmsMode,
 ) -> Vec<(Bbox, f32)> {
-    let mut selected = vec![];
-    'candidates: loop {
-        // Get next most confident bbox from the back of ascending-sorted vector.
-        // All boxes fulfill the minimum confidence criterium.
-        match sorted_bboxes_with_confidences.pop() {
-            Some((bbox, confidence)) => {
-                // Check for overlap with any of the selected bboxes
-                for (selected_bbox, _) in selected.iter() {
-                    match iou(bbox, selected_bbox) {
-                        x if x > max_iou => continue 'candidates,
-                        _ => (),
-                    }
-                }
+    let use_grid = sorted_bboxes_with_confidences.len() >= NMS_GRID_MIN_CANDIDATES;
+    suppress(sorted_bboxes_with_confidences, max_iou, mode, use_grid)
+}
 
-                // bbox has no large overlap with any of the selected ones, add it
-                selected.push((*bbox, *confidence))
+/// `non_maximum_suppression` with or without the `NmsGrid`.
+fn suppress(
+    mut sorted_bboxes_with_confidences: Vec<(&Bbox, &f32, Option<usize>)>,
+    max_iou: f32,
+    mode: NmsMode,
+    use_grid: bool,
+) -> Vec<(Bbox, f32)> {
+    let mut grid = use_grid.then(|| {
+        NmsGrid::new(
+            sorted_bboxes_with_confidences
+                .iter()
+                .map(|(bbox, _, _)| *bbox),
+        )
+    });
+    let mut selected: Vec<(Bbox, f32, Option<usize>)> = vec![];
+    // confidence weighted sum of the boxes in each selected box's cluster, and the sum of weights
+    let mut clusters: Vec<(Bbox, f32)> = vec![];
+    // Get next most confident bbox from the back of ascending-sorted vector.
+    // All boxes fulfill the minimum confidence criterium.
+    while let Some((bbox, confidence, class)) = sorted_bboxes_with_confidences.pop() {
+        // Find the first selected bbox of the same class it overlaps with
+        let overlaps = |index: &usize| {
+            let (selected_bbox, _, selected_class) = &selected[*index];
+            iou(bbox, selected_bbox) > max_iou && class == *selected_class
+        };
+        let overlapping = match &grid {
+            Some(grid) => grid.nearby(bbox).into_iter().find(overlaps),
+            None => (0..selected.len()).find(overlaps),
+        };
+
+        match overlapping {
+            Some(index) => {
+                let (weighted_sum, weight) = &mut clusters[index];
+                for (sum, value) in weighted_sum.iter_mut().zip(bbox) {
+                    *sum += value * confidence;
+                }
+                *weight += confidence;
+            }
+            // bbox has no large overlap with any of the selected ones, add it
+            None => {
+                if let Some(grid) = grid.as_mut() {
+                    grid.insert(selected.len(), bbox);
+                }
+                selected.push((*bbox, *confidence, class));
+                clusters.push((bbox.map(|value| value * confidence), *confidence));
             }
-            None => break 'candidates,
         }
     }
 
     selected
+        .into_iter()
+        .zip(clusters)
+        .map(
+            |((bbox, confidence, _), (weighted_sum, weight))| match mode {
+                NmsMode::WeightedFusion if weight > 0.0 => {
+                    (weighted_sum.map(|sum| sum / weight), confidence)
+                }
+                _ => (bbox, confidence),
+            },
+        )
+        .collect()
+}
+
+/// Selected boxes by the cells of a grid over the candidates they cover. Boxes with an IoU above 0
+/// intersect, so they always share a cell. Boxes with coordinates that are not finite are
+/// compared to every candidate.
+struct NmsGrid {
+    origin: [f32; 2],
+    cell_size: [f32; 2],
+    cells: Vec<Vec<usize>>,
+    unbounded: Vec<usize>,
+}
+
+impl NmsGrid {
+    fn new<'a>(bboxes: impl Iterator<Item = &'a Bbox>) -> NmsGrid {
+        let (mut min, mut max) = ([f32::MAX; 2], [f32::MIN; 2]);
+        for bbox in bboxes.filter(|bbox| bbox.iter().all(|value| value.is_finite())) {
+            for axis in 0..2 {
+                min[axis] = min[axis].min(bbox[axis]);
+                max[axis] = max[axis].max(bbox[axis + 2]);
+            }
+        }
+        let cell_size = [0, 1].map(|axis| (max[axis] - min[axis]).max(EPS) / NMS_GRID_SIZE as f32);
+        NmsGrid {
+            origin: min,
+            cell_size,
+            cells: vec![vec![]; NMS_GRID_SIZE * NMS_GRID_SIZE],
+            unbounded: vec![],
+        }
+    }
+
+    /// Cells covered by `bbox`, `None` when it is not finite.
+    fn cells(&self, bbox: &Bbox) -> Option<impl Iterator<Item = usize>> {
+        if !bbox.iter().all(|value| value.is_finite()) {
+            return None;
+        }
+        // clamping keeps the order of coordinates, so intersecting boxes still share a cell
+        let cell = |value: f32, axis: usize| {
+            let cell = ((value - self.origin[axis]) / self.cell_size[axis]).floor();
+            cell.clamp(0.0, (NMS_GRID_SIZE - 1) as f32) as usize
+        };
+        let (x1, x2) = (cell(bbox[0], 0), cell(bbox[2], 0));
+        let (y1, y2) = (cell(bbox[1], 1), cell(bbox[3], 1));
+        Some((y1..=y2).flat_map(move |y| (x1..=x2).map(move |x| y * NMS_GRID_SIZE + x)))
+    }
+
+    fn insert(&mut self, index: usize, bbox: &Bbox) {
+        match self.cells(bbox) {
+            Some(cells) => {
+                for cell in cells.collect::<Vec<_>>() {
+                    self.cells[cell].push(index);
+                }
+            }
+            None => self.unbounded.push(index),
+        }
+    }
+
+    /// Indices of the selected boxes that may overlap `bbox`, in ascending order so the first
+    /// overlapping box is the same the naive comparison finds.
+    fn nearby(&self, bbox: &Bbox) -> Vec<usize> {
+        let mut nearby = self.unbounded.clone();
+        match self.cells(bbox) {
+            Some(cells) => {
+                for cell in cells {
+                    nearby.extend(&self.cells[cell]);
+                }
+            }
+            None => nearby.extend(self.cells.iter().flatten()),
+        }
+        nearby.sort_unstable();
+        nearby.dedup();
+        nearby
+    }
 }
 
 /// Calculate the intersection-over-union metric for two bounding boxes.
@@ -184,6 +859,17 @@ fn iou(bbox_a: &Bbox, bbox_b: &Bbox) -> f32 {
     overlap_area / (bbox_area(bbox_a) + bbox_area(bbox_b) - overlap_area + EPS)
 }
 
+/// Whether the width:height of `bbox` is within `(min, max)`, false for boxes without width or
+/// height, which inverted model boxes can end up as.
+pub fn has_aspect_ratio(bbox: &BboxPixels, (min, max): (f32, f32)) -> bool {
+    let [x1, y1, x2, y2] = *bbox;
+    if x2 <= x1 || y2 <= y1 {
+        return false;
+    }
+    let aspect_ratio = (x2 - x1) as f32 / (y2 - y1) as f32;
+    (min..=max).contains(&aspect_ratio)
+}
+
 /// Calculate the area enclosed by a bounding box.
 ///
 /// The bounding box is passed as four-element array defining two points:
@@ -201,7 +887,9 @@ fn bbox_area(bbox: &Bbox) -> f32 {
     width * height
 }
 
-fn map_bboxes_to_bbox_with_pixels(
+/// Map boxes relative to the model input to pixels of the original `image_width` x
+/// `image_height` image.
+pub fn map_bboxes_to_bbox_with_pixels(
     image_width: u32,
     image_height: u32,
     sorted_bboxes_with_confidences: Vec<(Bbox, f32)>,
@@ -216,6 +904,8 @@ fn map_bboxes_to_bbox_with_pixels(
         .collect()
 }
 
+/// Map a box relative to the model input back to pixels of the original image, undoing the center
+/// crop of `resize_to_fill`. Boxes are clamped to the image.
 fn get_bbox_pixel_locations(image_width: f32, image_height: f32, output_bbox: Bbox) -> BboxPixels {
     let aspect_ratio_raw_image = image_width / image_height;
     let (x_tl, y_tl, x_br, y_br): (f32, f32, f32, f32) = if aspect_ratio_raw_image > ULTRA_RATIO {
@@ -245,5 +935,335 @@ fn get_bbox_pixel_locations(image_width: f32, image_height: f32, output_bbox: Bb
             output_bbox[3] * image_height,
         )
     };
-    [x_tl as u32, y_tl as u32, x_br as u32, y_br as u32]
+    [
+        x_tl.clamp(0.0, image_width) as u32,
+        y_tl.clamp(0.0, image_height) as u32,
+        x_br.clamp(0.0, image_width) as u32,
+        y_br.clamp(0.0, image_height) as u32,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array;
+
+    use super::*;
+
+    /// Two boxes with an IoU of about 0.82, one of each face class.
+    static OVERLAPPING: [(Bbox, f32, usize); 2] = [
+        ([0.11, 0.11, 0.31, 0.31], 0.8, 2),
+        ([0.10, 0.10, 0.30, 0.30], 0.9, 1),
+    ];
+
+    #[test]
+    fn class_agnostic_nms_suppresses_other_classes() {
+        let candidates = OVERLAPPING
+            .iter()
+            .map(|(bbox, confidence, _)| (bbox, confidence, None))
+            .collect();
+        let selected = non_maximum_suppression(candidates, MAX_IOU, NmsMode::Hard);
+        assert_eq!(selected, vec![([0.10, 0.10, 0.30, 0.30], 0.9)]);
+    }
+
+    #[test]
+    fn per_class_nms_keeps_other_classes() {
+        let candidates = OVERLAPPING
+            .iter()
+            .map(|(bbox, confidence, class)| (bbox, confidence, Some(*class)))
+            .collect();
+        let selected = non_maximum_suppression(candidates, MAX_IOU, NmsMode::Hard);
+        assert_eq!(
+            selected,
+            vec![
+                ([0.10, 0.10, 0.30, 0.30], 0.9),
+                ([0.11, 0.11, 0.31, 0.31], 0.8)
+            ]
+        );
+    }
+
+    /// Class-agnostic non-maximum-suppression of unsorted candidates.
+    fn nms(candidates: &[(Bbox, f32)], mode: NmsMode) -> Vec<(Bbox, f32)> {
+        let mut sorted: Vec<_> = candidates
+            .iter()
+            .map(|(bbox, confidence)| (bbox, confidence, None))
+            .collect();
+        sorted.sort_by(|a, b| a.1.total_cmp(b.1));
+        non_maximum_suppression(sorted, MAX_IOU, mode)
+    }
+
+    fn assert_close(actual: &[(Bbox, f32)], expected: &[(Bbox, f32)]) {
+        assert_eq!(
+            actual.len(),
+            expected.len(),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+        for ((bbox, confidence), (expected_bbox, expected_confidence)) in
+            actual.iter().zip(expected)
+        {
+            for (value, expected_value) in bbox.iter().zip(expected_bbox) {
+                assert!(
+                    (value - expected_value).abs() < 1e-6,
+                    "{:?} != {:?}",
+                    bbox,
+                    expected_bbox
+                );
+            }
+            assert!((confidence - expected_confidence).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn weighted_fusion_averages_clusters_by_confidence() {
+        let candidates = vec![
+            ([0.10, 0.10, 0.30, 0.30], 0.9),
+            ([0.12, 0.12, 0.32, 0.32], 0.6),
+            ([0.08, 0.08, 0.28, 0.28], 0.5),
+            ([0.60, 0.60, 0.80, 0.80], 0.7),
+        ];
+        let selected = nms(&candidates, NmsMode::WeightedFusion);
+        // (0.9 * 0.10 + 0.6 * 0.12 + 0.5 * 0.08) / 2.0 = 0.101, the far box has no cluster
+        assert_close(
+            &selected,
+            &[
+                ([0.101, 0.101, 0.301, 0.301], 0.9),
+                ([0.60, 0.60, 0.80, 0.80], 0.7),
+            ],
+        );
+
+        let hard = nms(&candidates, NmsMode::Hard);
+        assert_close(
+            &hard,
+            &[
+                ([0.10, 0.10, 0.30, 0.30], 0.9),
+                ([0.60, 0.60, 0.80, 0.80], 0.7),
+            ],
+        );
+    }
+
+    #[test]
+    fn weighted_fusion_of_equal_confidences_is_the_mean() {
+        let candidates = vec![
+            ([0.00, 0.00, 0.20, 0.20], 0.8),
+            ([0.02, 0.02, 0.22, 0.22], 0.8),
+        ];
+        let selected = nms(&candidates, NmsMode::WeightedFusion);
+        assert_close(&selected, &[([0.01, 0.01, 0.21, 0.21], 0.8)]);
+    }
+
+    /// `count` boxes of 0.02 to 0.1 sides scattered over the input, with clusters to suppress,
+    /// sorted by ascending confidence. Deterministic, from a linear congruential generator.
+    fn synthetic_candidates(count: usize) -> Vec<(Bbox, f32)> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 40) as f32 / (1u64 << 24) as f32
+        };
+        let mut candidates: Vec<(Bbox, f32)> = (0..count)
+            .map(|_| {
+                let (x, y, size) = (random(), random(), 0.02 + random() * 0.08);
+                ([x, y, x + size, y + size], random())
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+        candidates
+    }
+
+    fn suppress_candidates(
+        candidates: &[(Bbox, f32)],
+        mode: NmsMode,
+        use_grid: bool,
+    ) -> Vec<(Bbox, f32)> {
+        let candidates = candidates
+            .iter()
+            .map(|(bbox, confidence)| (bbox, confidence, None))
+            .collect();
+        suppress(candidates, MAX_IOU, mode, use_grid)
+    }
+
+    #[test]
+    fn grid_nms_matches_naive_nms() {
+        let candidates = synthetic_candidates(3000);
+        for mode in [NmsMode::Hard, NmsMode::WeightedFusion] {
+            let naive = suppress_candidates(&candidates, mode, false);
+            let grid = suppress_candidates(&candidates, mode, true);
+            assert!(naive.len() < candidates.len());
+            assert_eq!(grid, naive);
+        }
+    }
+
+    /// Timing of both paths, run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_grid_nms() {
+        for count in [1000, 3000, 10000] {
+            let candidates = synthetic_candidates(count);
+            for use_grid in [false, true] {
+                let start = Instant::now();
+                let selected = suppress_candidates(&candidates, NmsMode::Hard, use_grid);
+                println!(
+                    "{} candidates, grid {}: {} selected in {:?}",
+                    count,
+                    use_grid,
+                    selected.len(),
+                    start.elapsed()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn image_tensor_shape_follows_input_layout() {
+        let image = RgbImage::from_fn(
+            ULTRA_INPUT_WIDTH as u32,
+            ULTRA_INPUT_HEIGHT as u32,
+            |x, y| image::Rgb([x as u8, y as u8, (x + y) as u8]),
+        );
+        let nchw = get_image_tensor(&image, InputLayout::Nchw, ChannelOrder::Rgb);
+        assert_eq!(nchw.shape(), [1, 3, ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH]);
+        let nhwc = get_image_tensor(&image, InputLayout::Nhwc, ChannelOrder::Rgb);
+        assert_eq!(nhwc.shape(), [1, ULTRA_INPUT_HEIGHT, ULTRA_INPUT_WIDTH, 3]);
+        // the same values, only the axes are permuted
+        assert_eq!(nchw.view().permuted_axes(vec![0, 2, 3, 1]), nhwc.view());
+    }
+
+    #[test]
+    fn channel_order_places_red_first_or_last() {
+        let red = RgbImage::from_pixel(
+            ULTRA_INPUT_WIDTH as u32,
+            ULTRA_INPUT_HEIGHT as u32,
+            image::Rgb([255, 0, 0]),
+        );
+        // red normalized with its own mean and std, the other channels are at zero intensity
+        let normalized_red = (1.0 - 0.485) / 0.229;
+        let rgb = get_image_tensor(&red, InputLayout::Nchw, ChannelOrder::Rgb);
+        assert!((rgb[[0, 0, 0, 0]] - normalized_red).abs() < 1e-6);
+        assert!(rgb[[0, 2, 0, 0]] < 0.0);
+        let bgr = get_image_tensor(&red, InputLayout::Nchw, ChannelOrder::Bgr);
+        assert!((bgr[[0, 2, 0, 0]] - normalized_red).abs() < 1e-6);
+        assert!(bgr[[0, 0, 0, 0]] < 0.0);
+        let bgr_nhwc = get_image_tensor(&red, InputLayout::Nhwc, ChannelOrder::Bgr);
+        assert!((bgr_nhwc[[0, 0, 0, 2]] - normalized_red).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bbox_pixel_locations_undo_the_center_crop() {
+        let bbox = [0.25, 0.25, 0.75, 0.75];
+        // wider than 4:3, the crop is 1200 wide and starts 200 pixels in
+        assert_eq!(
+            get_bbox_pixel_locations(1600.0, 900.0, bbox),
+            [500, 225, 1100, 675]
+        );
+        // taller than 4:3, the crop is 675 high and starts 462.5 pixels down
+        assert_eq!(
+            get_bbox_pixel_locations(900.0, 1600.0, bbox),
+            [225, 631, 675, 968]
+        );
+        // 4:3 is not cropped, only scaled
+        assert_eq!(
+            get_bbox_pixel_locations(1280.0, 960.0, bbox),
+            [320, 240, 960, 720]
+        );
+        // boxes reaching past the input are clamped to the image
+        assert_eq!(
+            get_bbox_pixel_locations(1280.0, 960.0, [-0.1, -0.1, 1.1, 1.1]),
+            [0, 0, 1280, 960]
+        );
+    }
+
+    /// Box of the bright pixels of a model input, relative to it like a model output box.
+    fn bright_bbox(input: &RgbImage) -> Bbox {
+        let (mut min, mut max) = ([u32::MAX; 2], [0; 2]);
+        for (x, y, pixel) in input.enumerate_pixels() {
+            if pixel[0] > 127 {
+                min = [min[0].min(x), min[1].min(y)];
+                max = [max[0].max(x + 1), max[1].max(y + 1)];
+            }
+        }
+        let (width, height) = (input.width() as f32, input.height() as f32);
+        [
+            min[0] as f32 / width,
+            min[1] as f32 / height,
+            max[0] as f32 / width,
+            max[1] as f32 / height,
+        ]
+    }
+
+    #[test]
+    fn prescaled_detections_match_unprescaled_detections() {
+        // in pixels of the original image, the input shows about 3 of them per input pixel
+        let tolerance = 10;
+        for (width, height) in [(2400, 1400), (1400, 2400)] {
+            let face = [
+                width / 2 - 200,
+                height / 2 - 150,
+                width / 2 + 200,
+                height / 2 + 150,
+            ];
+            let image = DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+                let inside = (face[0]..face[2]).contains(&x) && (face[1]..face[3]).contains(&y);
+                image::Rgb(match inside {
+                    true => [255, 255, 255],
+                    false => [0, 0, 0],
+                })
+            }));
+            let detect = |prescale: bool| {
+                let input = resize_for_model(&image, FilterType::Triangle, prescale);
+                let bbox = bright_bbox(&input);
+                map_bboxes_to_bbox_with_pixels(width, height, vec![(bbox, 1.0)])[0].0
+            };
+            let (prescaled, unprescaled) = (detect(true), detect(false));
+            for ((prescaled, unprescaled), expected) in prescaled.iter().zip(unprescaled).zip(face)
+            {
+                assert!(prescaled.abs_diff(unprescaled) <= tolerance);
+                assert!(prescaled.abs_diff(expected) <= tolerance);
+            }
+        }
+    }
+
+    #[test]
+    fn post_processing_survives_nan_outputs() {
+        let candidates: Vec<Candidate> = vec![
+            ([0.1, 0.1, 0.3, 0.3], 1, f32::NAN),
+            ([0.1, 0.1, 0.3, 0.3], 1, 0.9),
+            ([f32::NAN, 0.1, 0.3, f32::NAN], 1, 0.8),
+            ([0.3, 0.3, 0.1, 0.1], 1, 0.95),
+        ];
+        for nms_mode in [NmsMode::Hard, NmsMode::WeightedFusion] {
+            let bboxes_with_confidences =
+                post_process(&candidates, 0.5, 0.0, false, nms_mode, 1000);
+            assert!(bboxes_with_confidences
+                .iter()
+                .all(|(_, confidence)| !confidence.is_nan()));
+            let detections = map_bboxes_to_bbox_with_pixels(640, 480, bboxes_with_confidences);
+            merge_detections(detections);
+        }
+        merge_detections(vec![([0, 0, 10, 10], f32::NAN), ([0, 0, 10, 10], 0.9)]);
+    }
+
+    #[test]
+    fn has_aspect_ratio_rejects_inverted_boxes() {
+        assert!(has_aspect_ratio(&[10, 10, 20, 20], (0.5, 2.0)));
+        assert!(!has_aspect_ratio(&[20, 10, 10, 20], (0.0, f32::MAX)));
+        assert!(!has_aspect_ratio(&[10, 20, 20, 10], (0.0, f32::MAX)));
+        assert!(!has_aspect_ratio(&[10, 10, 10, 20], (0.0, f32::MAX)));
+    }
+
+    #[test]
+    fn decode_candidates_rejects_misshaped_outputs() {
+        let bboxes = Array::from_elem((1, 3, 4), 0.5f32).into_dyn();
+        // a background column without any face class
+        let confidences = Array::from_elem((1, 3, 1), 0.5f32).into_dyn();
+        let decoded = decode_candidates(confidences.view(), bboxes.view(), None);
+        assert!(matches!(decoded, Err(Error::ModelOutput(_))));
+
+        // boxes with 3 instead of 4 coordinates
+        let confidences = Array::from_elem((1, 3, 2), 0.5f32).into_dyn();
+        let bboxes = Array::from_elem((1, 3, 3), 0.5f32).into_dyn();
+        let decoded = decode_candidates(confidences.view(), bboxes.view(), None);
+        assert!(matches!(decoded, Err(Error::ModelOutput(_))));
+    }
 }
@@ -0,0 +1,43 @@
+use std::fmt::Write;
+
+use crate::detection::DetectionResult;
+
+/// Class name of every object in a VOC annotation.
+static VOC_CLASS: &str = "face";
+
+/// Serialize a result as a Pascal VOC annotation of the image `filename`, one `face` object per
+/// detection, so results can be fed to VOC based training tooling as they are.
+pub fn voc_annotation(result: &DetectionResult, filename: &str) -> String {
+    let mut xml = String::new();
+    xml.push_str("<annotation>\n");
+    let _ = writeln!(xml, "  <filename>{}</filename>", escape(filename));
+    let _ = writeln!(
+        xml,
+        "  <size>\n    <width>{}</width>\n    <height>{}</height>\n    <depth>3</depth>\n  </size>",
+        result.image.width, result.image.height
+    );
+    xml.push_str("  <segmented>0</segmented>\n");
+    for ([x1, y1, x2, y2], _) in &result.detections {
+        // boxes are clipped to the image, a box on its border is cut off by it
+        let truncated =
+            *x1 == 0 || *y1 == 0 || *x2 >= result.image.width || *y2 >= result.image.height;
+        let _ = writeln!(
+            xml,
+            "  <object>\n    <name>{}</name>\n    <pose>Unspecified</pose>\n    \
+             <truncated>{}</truncated>\n    <difficult>0</difficult>\n    <bndbox>\n      \
+             <xmin>{}</xmin>\n      <ymin>{}</ymin>\n      <xmax>{}</xmax>\n      \
+             <ymax>{}</ymax>\n    </bndbox>\n  </object>",
+            VOC_CLASS, truncated as u8, x1, y1, x2, y2
+        );
+    }
+    xml.push_str("</annotation>\n");
+    xml
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
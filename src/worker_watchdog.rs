@@ -0,0 +1,80 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::Duration,
+};
+
+use actix_rt::time;
+
+/// Longest wait between two restarts, the backoff stops doubling there.
+static MAX_RESTART_BACKOFF_SECS: u64 = 60;
+
+/// Supervises the queue worker: a worker that panicked or returned is restarted after a
+/// doubling backoff, until `max_restarts` is used up. Without it the queue stops draining
+/// silently while uploads keep being accepted.
+pub struct WorkerWatchdog {
+    max_restarts: u32,
+    backoff: Duration,
+    restarts: AtomicU32,
+    alive: AtomicBool,
+}
+
+impl WorkerWatchdog {
+    pub fn new(max_restarts: u32, backoff: Duration) -> WorkerWatchdog {
+        WorkerWatchdog {
+            max_restarts,
+            backoff,
+            restarts: AtomicU32::new(0),
+            alive: AtomicBool::new(true),
+        }
+    }
+
+    /// `false` once the worker died and was not restarted, the queue is no longer processed.
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Run the worker `start_worker` returns and start it again whenever it ends. `recover` is
+    /// called once it panicked, to fail the jobs it had taken off the queue.
+    pub async fn supervise<F, Fut, R>(&self, start_worker: F, recover: R)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ()> + 'static,
+        R: Fn(),
+    {
+        loop {
+            match actix_rt::spawn(start_worker()).await {
+                Ok(()) => println!("queue worker exited"),
+                Err(err) => {
+                    println!("queue worker panicked; {}", err);
+                    recover();
+                }
+            }
+            let restarts = self.restarts();
+            if restarts >= self.max_restarts {
+                self.alive.store(false, Ordering::Relaxed);
+                println!(
+                    "queue worker died after {} restarts, queued jobs are no longer processed",
+                    restarts
+                );
+                return;
+            }
+            let backoff = self
+                .backoff
+                .saturating_mul(2u32.saturating_pow(restarts))
+                .min(Duration::from_secs(MAX_RESTART_BACKOFF_SECS));
+            println!(
+                "restarting queue worker in {:?} ({} of {})",
+                backoff,
+                restarts + 1,
+                self.max_restarts
+            );
+            time::sleep(backoff).await;
+            self.restarts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}